@@ -0,0 +1,35 @@
+CREATE TABLE IF NOT EXISTS recipes (
+  name VARCHAR(100) PRIMARY KEY,
+  current_version INT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS recipe_versions (
+  recipe_name VARCHAR(100) REFERENCES recipes (name) ON DELETE CASCADE,
+  version INT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (recipe_name, version)
+);
+
+CREATE TABLE IF NOT EXISTS recipe_ingredients (
+  recipe_name VARCHAR(100),
+  version INT,
+  ingredient VARCHAR(100),
+  -- kept to the millionth, matching what the app holds in memory
+  amount NUMERIC(32, 6) NOT NULL,
+  unit VARCHAR(10),
+  PRIMARY KEY (recipe_name, version, ingredient),
+  FOREIGN KEY (recipe_name, version) REFERENCES recipe_versions ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS pantries (
+  id SERIAL PRIMARY KEY,
+  owner VARCHAR(100) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pantry_items (
+  pantry_id INT REFERENCES pantries (id) ON DELETE CASCADE,
+  ingredient VARCHAR(100),
+  amount NUMERIC(32, 6) NOT NULL,
+  unit VARCHAR(10),
+  PRIMARY KEY (pantry_id, ingredient)
+);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS orders (
+  id INT PRIMARY KEY,
+  region_id INT,
+  gift_name VARCHAR(50),
+  quantity INT
+);
+
+-- day13 and day18 use the copies in each ns_* schema, this only defines
+-- the columns they are created with
+COMMENT ON TABLE orders IS 'template for namespaced orders tables, holds no rows';
+
+-- every ns_* schema, with the template version its tables were built from
+CREATE TABLE IF NOT EXISTS namespaces (
+  name VARCHAR(32) PRIMARY KEY,
+  schema_version BIGINT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS regions (
+  id INT PRIMARY KEY,
+  name VARCHAR(50)
+);
+
+-- day13 and day18 use the copies in each ns_* schema, this only defines
+-- the columns they are created with
+COMMENT ON TABLE regions IS 'template for namespaced regions tables, holds no rows';
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS kv_store (
+  key VARCHAR(200) PRIMARY KEY,
+  value BYTEA NOT NULL
+);
@@ -1,7 +0,0 @@
-DROP TABLE IF EXISTS orders;
-CREATE TABLE orders (
-  id INT PRIMARY KEY,
-  region_id INT,
-  gift_name VARCHAR(50),
-  quantity INT
-);
\ No newline at end of file
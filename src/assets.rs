@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use actix_web::{
+    error::{self, BlockingError},
+    http::{header::ContentType, StatusCode},
+    HttpRequest, HttpResponse,
+};
+use derive_more::{Display, Error};
+use mime_guess::mime::{self, Mime};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Display, Error)]
+pub enum AssetError {
+    #[display(fmt = "asset not found")]
+    NotFound,
+
+    #[display(fmt = "invalid asset path")]
+    InvalidPath,
+
+    #[display(fmt = "missing or wrong asset token")]
+    Unauthorized,
+
+    #[display(fmt = "asset management is disabled")]
+    ManagementDisabled,
+
+    #[display(fmt = "asset storage failed")]
+    Io(io::Error),
+
+    #[display(fmt = "interrupted before finishing")]
+    Interrupted,
+}
+
+impl From<io::Error> for AssetError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => AssetError::NotFound,
+            _ => AssetError::Io(err),
+        }
+    }
+}
+
+impl From<BlockingError> for AssetError {
+    fn from(_: BlockingError) -> Self {
+        AssetError::Interrupted
+    }
+}
+
+impl error::ResponseError for AssetError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            AssetError::NotFound => StatusCode::NOT_FOUND,
+            AssetError::InvalidPath => StatusCode::BAD_REQUEST,
+            AssetError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AssetError::ManagementDisabled => StatusCode::FORBIDDEN,
+            AssetError::Io(_) | AssetError::Interrupted => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AssetInfo {
+    pub path: String,
+    pub size: u64,
+    pub content_type: String,
+    pub etag: String,
+}
+
+/// Files under a single root directory, with every lookup checked to stay
+/// inside it after symlinks are resolved. The methods block on the filesystem
+/// and hash whole files, so handlers call them inside `web::block`.
+pub struct AssetStore {
+    root: PathBuf,
+    token: Option<String>,
+    // sha-256 etags, reused while the file's mtime and size are unchanged
+    etags: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+}
+
+impl AssetStore {
+    pub fn new(root: impl AsRef<Path>, token: Option<String>) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+
+        Ok(AssetStore {
+            root: root.as_ref().canonicalize()?,
+            token,
+            etags: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Checks the `Authorization: Bearer <token>` header of a management call.
+    pub fn authorize(&self, req: &HttpRequest) -> Result<(), AssetError> {
+        let token = self.token.as_ref().ok_or(AssetError::ManagementDisabled)?;
+        if !bearer_matches(req, token) {
+            return Err(AssetError::Unauthorized);
+        }
+
+        Ok(())
+    }
+
+    /// Joins a request path onto the root, refusing anything but plain names.
+    fn join(&self, path: &str) -> Result<PathBuf, AssetError> {
+        let relative = Path::new(path);
+        let plain = relative.components().all(|component| match component {
+            Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+            _ => false,
+        });
+        if path.is_empty() || !plain {
+            return Err(AssetError::InvalidPath);
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    fn check_inside(&self, canonical: &Path) -> Result<(), AssetError> {
+        if canonical.starts_with(&self.root) {
+            Ok(())
+        } else {
+            Err(AssetError::InvalidPath)
+        }
+    }
+
+    /// Canonical path of an existing asset file.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, AssetError> {
+        let canonical = self.join(path)?.canonicalize()?;
+        self.check_inside(&canonical)?;
+
+        if !canonical.is_file() {
+            return Err(AssetError::NotFound);
+        }
+
+        Ok(canonical)
+    }
+
+    pub fn store(&self, path: &str, contents: &[u8]) -> Result<AssetInfo, AssetError> {
+        let target = self.join(path)?;
+        let parent = target.parent().ok_or(AssetError::InvalidPath)?;
+
+        // check the deepest existing directory before creating anything, a
+        // symlinked directory could otherwise point outside the root
+        let existing = parent
+            .ancestors()
+            .find(|dir| dir.exists())
+            .ok_or(AssetError::InvalidPath)?;
+        self.check_inside(&existing.canonicalize()?)?;
+
+        fs::create_dir_all(parent)?;
+        self.check_inside(&parent.canonicalize()?)?;
+        if target.is_symlink() || target.is_dir() {
+            return Err(AssetError::InvalidPath);
+        }
+
+        fs::write(&target, contents)?;
+
+        self.info(&target.canonicalize()?)
+    }
+
+    pub fn remove(&self, path: &str) -> Result<(), AssetError> {
+        let canonical = self.resolve(path)?;
+
+        fs::remove_file(&canonical)?;
+        self.etags.lock().unwrap().remove(&canonical);
+
+        Ok(())
+    }
+
+    /// Every asset file below the root, sorted by path.
+    pub fn list(&self) -> Result<Vec<AssetInfo>, AssetError> {
+        let mut pending = vec![self.root.clone()];
+        let mut assets = Vec::new();
+
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    pending.push(entry.path());
+                } else if file_type.is_file() {
+                    assets.push(self.info(&entry.path())?);
+                }
+            }
+        }
+
+        assets.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(assets)
+    }
+
+    pub fn info(&self, canonical: &Path) -> Result<AssetInfo, AssetError> {
+        let path = canonical
+            .strip_prefix(&self.root)
+            .map_err(|_| AssetError::InvalidPath)?
+            .to_string_lossy()
+            .to_string();
+
+        Ok(AssetInfo {
+            path,
+            size: fs::metadata(canonical)?.len(),
+            content_type: sniff_content_type(canonical)?.to_string(),
+            etag: self.etag(canonical)?,
+        })
+    }
+
+    /// Strong etag from the SHA-256 of the file contents.
+    pub fn etag(&self, canonical: &Path) -> Result<String, AssetError> {
+        let metadata = fs::metadata(canonical)?;
+        let modified = metadata.modified()?;
+
+        if let Some((cached_modified, cached_len, etag)) = self.etags.lock().unwrap().get(canonical)
+        {
+            if *cached_modified == modified && *cached_len == metadata.len() {
+                return Ok(etag.clone());
+            }
+        }
+
+        let mut file = fs::File::open(canonical)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)?;
+        let etag = format!("\"{}\"", hex::encode(hasher.finalize()));
+
+        self.etags.lock().unwrap().insert(
+            canonical.to_path_buf(),
+            (modified, metadata.len(), etag.clone()),
+        );
+
+        Ok(etag)
+    }
+}
+
+/// Whether the request's `Authorization: Bearer <token>` header holds `token`.
+pub fn bearer_matches(req: &HttpRequest, token: &str) -> bool {
+    let given = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    // comparing digests keeps the comparison time independent of the token
+    given.is_some_and(|given| Sha256::digest(given) == Sha256::digest(token))
+}
+
+/// Picks a content type from the file's leading bytes. The extension is only
+/// trusted to name a text format once the contents are known to be text.
+pub fn sniff_content_type(path: &Path) -> io::Result<Mime> {
+    let mut head = Vec::with_capacity(512);
+    fs::File::open(path)?.take(512).read_to_end(&mut head)?;
+
+    if let Ok(format) = image::guess_format(&head) {
+        return Ok(format
+            .to_mime_type()
+            .parse()
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM));
+    }
+
+    let magic: &[(&[u8], &str)] = &[
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+    if let Some((_, mime)) = magic.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Ok(mime.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM));
+    }
+
+    // a utf-8 prefix can end mid character, so only reject real invalid bytes
+    let is_text = !head.contains(&0)
+        && match std::str::from_utf8(&head) {
+            Ok(_) => true,
+            Err(err) => err.error_len().is_none(),
+        };
+    if !is_text {
+        return Ok(mime::APPLICATION_OCTET_STREAM);
+    }
+
+    let guessed = mime_guess::from_path(path).first();
+    Ok(match guessed {
+        Some(guessed)
+            if guessed.type_() == mime::TEXT
+                || guessed.subtype() == mime::JSON
+                || guessed.subtype() == mime::JAVASCRIPT
+                || guessed.subtype() == mime::XML
+                || guessed.suffix() == Some(mime::XML) =>
+        {
+            guessed
+        }
+        _ => mime::TEXT_PLAIN_UTF_8,
+    })
+}
@@ -0,0 +1,136 @@
+//! Shows or applies the database migrations without starting the server.
+//!
+//! ```text
+//! DATABASE_URL=postgres://... cargo run --bin migrations -- [status|run]
+//! ```
+
+use std::{collections::BTreeMap, env, process};
+
+use sqlx::{migrate::Migrator, postgres::PgPoolOptions, PgPool};
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+struct Applied {
+    description: String,
+    installed_on: String,
+    success: bool,
+    checksum: Vec<u8>,
+}
+
+/// Migrations recorded in `_sqlx_migrations`. Only reads, so a database that
+/// was never migrated gets no table from a status check.
+async fn applied(pool: &PgPool) -> Result<BTreeMap<i64, Applied>, sqlx::Error> {
+    let table: Option<String> = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations')::TEXT")
+        .fetch_one(pool)
+        .await?;
+    if table.is_none() {
+        return Ok(BTreeMap::new());
+    }
+
+    let rows: Vec<(i64, String, String, bool, Vec<u8>)> = sqlx::query_as(
+        "SELECT version, description, date_trunc('second', installed_on)::TEXT, success, checksum
+        FROM _sqlx_migrations ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(version, description, installed_on, success, checksum)| {
+            let applied = Applied {
+                description,
+                installed_on,
+                success,
+                checksum,
+            };
+            (version, applied)
+        })
+        .collect())
+}
+
+/// Prints every migration with its state. Exits with 1 when the database is
+/// behind or out of step with migrations/.
+async fn status(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut applied = applied(pool).await?;
+    let mut in_step = true;
+
+    if applied.is_empty() {
+        println!("no migrations applied");
+    }
+
+    println!("{:>7}  {:<24}  status", "version", "description");
+    for migration in MIGRATOR.iter() {
+        let status = match applied.remove(&migration.version) {
+            None => {
+                in_step = false;
+                "pending".to_string()
+            }
+            Some(applied) if !applied.success => {
+                in_step = false;
+                "failed, needs fixing by hand".to_string()
+            }
+            Some(applied) if applied.checksum != *migration.checksum => {
+                in_step = false;
+                format!(
+                    "applied {}, but the file has changed since",
+                    applied.installed_on
+                )
+            }
+            Some(applied) => format!("applied {}", applied.installed_on),
+        };
+        println!(
+            "{:>7}  {:<24}  {}",
+            migration.version, migration.description, status
+        );
+    }
+
+    // applied by a newer build than this one
+    for (version, applied) in applied {
+        in_step = false;
+        println!(
+            "{:>7}  {:<24}  applied {}, missing from migrations/",
+            version, applied.description, applied.installed_on
+        );
+    }
+
+    if !in_step {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let command = env::args().nth(1).unwrap_or_else(|| "status".to_string());
+    let url = env::var("DATABASE_URL").unwrap_or_else(|_| {
+        eprintln!("DATABASE_URL must be set");
+        process::exit(2);
+    });
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&url)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("could not connect: {}", err);
+            process::exit(2);
+        });
+
+    let result = match command.as_str() {
+        "status" => status(&pool).await,
+        "run" => MIGRATOR
+            .run(&pool)
+            .await
+            .map_err(|err| sqlx::Error::Migrate(Box::new(err))),
+        _ => {
+            eprintln!("usage: migrations [status|run]");
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,283 @@
+//! The time endpoints treat as now.
+//!
+//! Production reads the system clock. With overrides enabled, the clock can
+//! be frozen or offset for every request through the `/clock` endpoints, or
+//! for a single request with an `X-Clock` header holding either an RFC 3339
+//! timestamp or a signed number of seconds, so results can be reproduced.
+//!
+//! Adjustments only change what requests see. Anything that destroys data,
+//! such as removing expired packets, goes by the system clock.
+
+use std::sync::RwLock;
+
+use actix_web::{
+    delete, error, get,
+    http::{header::ContentType, StatusCode},
+    put, web, HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Duration, Utc};
+use derive_more::{Display, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::AppState;
+
+pub const OVERRIDE_HEADER: &str = "X-Clock";
+
+// about a thousand years either way, which keeps the arithmetic in range
+const MAX_OFFSET: i64 = 1000 * 31_556_952;
+
+#[derive(Debug, Display, Error)]
+pub enum ClockError {
+    #[display(fmt = "clock overrides are disabled")]
+    Disabled,
+
+    #[display(
+        fmt = "{} must be an RFC 3339 timestamp or a number of seconds",
+        OVERRIDE_HEADER
+    )]
+    InvalidOverride,
+
+    #[display(fmt = "clock offset must be within {} seconds", MAX_OFFSET)]
+    OffsetOutOfRange,
+}
+
+impl error::ResponseError for ClockError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            ClockError::Disabled => StatusCode::FORBIDDEN,
+            ClockError::InvalidOverride | ClockError::OffsetOutOfRange => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Adjustment {
+    /// Always this time.
+    Frozen(DateTime<Utc>),
+    /// Seconds added to the system clock.
+    Offset(i64),
+}
+
+impl Adjustment {
+    fn check(self) -> Result<Self, ClockError> {
+        match self {
+            Adjustment::Offset(seconds) if seconds.abs() > MAX_OFFSET => {
+                Err(ClockError::OffsetOutOfRange)
+            }
+            adjustment => Ok(adjustment),
+        }
+    }
+
+    fn apply(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Adjustment::Frozen(time) => time,
+            Adjustment::Offset(seconds) => now + Duration::seconds(seconds),
+        }
+    }
+}
+
+pub struct Clock {
+    overridable: bool,
+    adjustment: RwLock<Option<Adjustment>>,
+}
+
+impl Clock {
+    pub fn new(overridable: bool) -> Self {
+        Clock {
+            overridable,
+            adjustment: RwLock::new(None),
+        }
+    }
+
+    /// Now following the clock-wide adjustment.
+    pub fn now(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+
+        match *self.adjustment.read().unwrap() {
+            Some(adjustment) => adjustment.apply(now),
+            None => now,
+        }
+    }
+
+    /// Now for a request, where an override header beats the clock-wide
+    /// adjustment. A malformed header is always refused, so a client finds
+    /// out whatever the deployment. A valid one is ignored unless overrides
+    /// are enabled.
+    pub fn now_for(&self, req: &HttpRequest) -> Result<DateTime<Utc>, ClockError> {
+        let Some(value) = req.headers().get(OVERRIDE_HEADER) else {
+            return Ok(self.now());
+        };
+
+        let value = value
+            .to_str()
+            .map_err(|_| ClockError::InvalidOverride)?
+            .trim();
+        let adjustment = match (value.parse::<DateTime<Utc>>(), value.parse::<i64>()) {
+            (Ok(time), _) => Adjustment::Frozen(time),
+            (_, Ok(seconds)) => Adjustment::Offset(seconds),
+            (Err(_), Err(_)) => return Err(ClockError::InvalidOverride),
+        }
+        .check()?;
+
+        if !self.overridable {
+            return Ok(Utc::now());
+        }
+        Ok(adjustment.apply(Utc::now()))
+    }
+
+    fn adjust(&self, adjustment: Option<Adjustment>) -> Result<(), ClockError> {
+        if !self.overridable {
+            return Err(ClockError::Disabled);
+        }
+
+        *self.adjustment.write().unwrap() = adjustment.map(Adjustment::check).transpose()?;
+        Ok(())
+    }
+
+    fn state(&self) -> serde_json::Value {
+        json!({
+            "now": self.now(),
+            "overridable": self.overridable,
+            "adjustment": *self.adjustment.read().unwrap(),
+        })
+    }
+}
+
+#[get("/clock")]
+pub async fn clock_get(data: web::Data<AppState>) -> impl Responder {
+    web::Json(data.clock.state())
+}
+
+/// Freezes or offsets the clock for every request, e.g. with
+/// `{"frozen": "2023-12-24T00:00:00Z"}` or `{"offset": -3600}`.
+#[put("/clock")]
+pub async fn clock_put(
+    adjustment: web::Json<Adjustment>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ClockError> {
+    data.clock.adjust(Some(adjustment.into_inner()))?;
+
+    Ok(web::Json(data.clock.state()))
+}
+
+/// Goes back to the system clock.
+#[delete("/clock")]
+pub async fn clock_delete(data: web::Data<AppState>) -> Result<impl Responder, ClockError> {
+    data.clock.adjust(None)?;
+
+    Ok(web::Json(data.clock.state()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        test::{self, TestRequest},
+        App,
+    };
+
+    fn time(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn header(value: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((OVERRIDE_HEADER, value))
+            .to_http_request()
+    }
+
+    #[actix_web::test]
+    async fn endpoints_freeze_and_restore_the_clock() {
+        let data = web::Data::new(AppState::for_tests());
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(clock_get)
+                .service(clock_put)
+                .service(clock_delete),
+        )
+        .await;
+
+        let frozen = json!({ "frozen": "2023-12-24T00:00:00Z" });
+        let req = TestRequest::put().uri("/clock").set_json(&frozen);
+        let state: serde_json::Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(state["adjustment"], frozen);
+        let req = TestRequest::get().uri("/clock");
+        let state: serde_json::Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(state["now"], "2023-12-24T00:00:00Z");
+
+        let plain = TestRequest::default().to_http_request();
+        assert_eq!(
+            data.clock.now_for(&plain).unwrap(),
+            time("2023-12-24T00:00:00Z")
+        );
+
+        let req = TestRequest::delete().uri("/clock");
+        let state: serde_json::Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(state["adjustment"], serde_json::Value::Null);
+        let drift = data.clock.now_for(&plain).unwrap() - Utc::now();
+        assert!(drift.num_seconds().abs() < 5);
+
+        let far = json!({ "offset": MAX_OFFSET + 1 });
+        let req = TestRequest::put().uri("/clock").set_json(&far);
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn header_beats_the_clock_wide_adjustment() {
+        let clock = Clock::new(true);
+        clock
+            .adjust(Some(Adjustment::Frozen(time("2023-12-24T00:00:00Z"))))
+            .unwrap();
+
+        assert_eq!(
+            clock.now_for(&header("2000-01-01T12:00:00+01:00")).unwrap(),
+            time("2000-01-01T11:00:00Z")
+        );
+
+        // an offset header moves the system clock, not the frozen one
+        let offset = clock.now_for(&header("3600")).unwrap() - Utc::now();
+        assert!((offset.num_seconds() - 3600).abs() < 5);
+    }
+
+    #[test]
+    fn refuses_malformed_headers_whether_or_not_overridable() {
+        for overridable in [true, false] {
+            let clock = Clock::new(overridable);
+
+            for value in ["tomorrow", "12.5", "2023-13-01T00:00:00Z", ""] {
+                assert!(matches!(
+                    clock.now_for(&header(value)),
+                    Err(ClockError::InvalidOverride)
+                ));
+            }
+            assert!(matches!(
+                clock.now_for(&header(&(MAX_OFFSET + 1).to_string())),
+                Err(ClockError::OffsetOutOfRange)
+            ));
+        }
+    }
+
+    #[test]
+    fn ignores_valid_headers_and_refuses_adjustments_when_not_overridable() {
+        let clock = Clock::new(false);
+
+        let now = clock.now_for(&header("2000-01-01T00:00:00Z")).unwrap();
+        assert!((now - Utc::now()).num_seconds().abs() < 5);
+        assert!(matches!(
+            clock.adjust(Some(Adjustment::Offset(60))),
+            Err(ClockError::Disabled)
+        ));
+    }
+}
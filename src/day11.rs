@@ -1,39 +1,1964 @@
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Cursor,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
 
 use actix_files::NamedFile;
-use actix_multipart::form::{bytes::Bytes, MultipartForm};
+use actix_multipart::form::{bytes::Bytes, text::Text, MultipartForm, MultipartFormConfig};
 use actix_web::{
-    get, post,
+    delete,
+    error::{self, BlockingError},
+    get,
+    http::{
+        header::{self, ContentType, HeaderValue},
+        StatusCode,
+    },
+    post, put,
     web::{self},
-    Responder,
+    HttpRequest, HttpResponse, Responder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use derive_more::{Display, Error};
+use image::{
+    imageops::FilterType, DynamicImage, GenericImageView, GrayImage, ImageFormat,
+    ImageOutputFormat, Luma, Rgb, RgbImage, Rgba,
 };
-use image::GenericImageView;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    assets::{sniff_content_type, AssetError, AssetInfo},
+    exif, AppState,
+};
+
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
+
+#[derive(Debug, Display, Error)]
+pub enum ImageError {
+    #[display(fmt = "could not decode image: {}", _0)]
+    Decode(image::ImageError),
+
+    #[display(
+        fmt = "unsupported image format, expected png, jpeg, gif, webp, bmp, ico, tiff, tga, pnm, qoi, hdr, exr, dds or farbfeld"
+    )]
+    UnsupportedFormat,
+
+    #[display(fmt = "{}", _0)]
+    TooLarge(#[error(not(source))] String),
+
+    #[display(fmt = "no image was uploaded")]
+    MissingImage,
+
+    #[display(fmt = "{}", _0)]
+    Asset(AssetError),
+
+    #[display(fmt = "invalid colour rules: {}", _0)]
+    InvalidRules(serde_json::Error),
+
+    #[display(fmt = "{}", _0)]
+    InvalidParameter(#[error(not(source))] String),
+
+    #[display(fmt = "could not encode image: {}", _0)]
+    Encode(image::ImageError),
+
+    #[display(fmt = "cannot encode {} images, expected png, jpeg or gif", _0)]
+    UnsupportedOutput(#[error(not(source))] String),
+
+    #[display(
+        fmt = "cannot strip metadata from an animated image of {} frames without dropping all but the first",
+        _0
+    )]
+    Animated(#[error(not(source))] u32),
+
+    #[display(fmt = "interrupted before finishing")]
+    Interrupted,
+}
+
+impl error::ResponseError for ImageError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            ImageError::Encode(_) | ImageError::Interrupted => StatusCode::INTERNAL_SERVER_ERROR,
+            ImageError::UnsupportedFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ImageError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ImageError::Asset(ref err) => err.status_code(),
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Upper bounds checked against an upload's headers before its pixels are
+/// decoded, so a small file claiming huge dimensions is refused cheaply.
+pub struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    pub max_frames: u32,
+    /// Bytes of uploads one multipart form may carry, all parts together.
+    pub max_upload_bytes: usize,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        ImageLimits {
+            max_width: 8192,
+            max_height: 8192,
+            max_pixels: 40_000_000,
+            max_frames: 100,
+            max_upload_bytes: 32 << 20,
+        }
+    }
+}
+
+impl ImageLimits {
+    /// Limits for the upload forms. Without it actix-multipart keeps at most
+    /// 2 MiB of a form's parts in memory, well short of several images.
+    pub fn multipart_config(&self) -> MultipartFormConfig {
+        MultipartFormConfig::default()
+            .total_limit(self.max_upload_bytes)
+            .memory_limit(self.max_upload_bytes)
+    }
+
+    /// Checks an upload against the limits and decodes it. Decoding, and
+    /// whatever a handler then does with the pixels, is cpu bound, so handlers
+    /// run both inside `web::block` to keep the async workers free.
+    fn decode(&self, bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+        let format = image::guess_format(bytes).map_err(|_| ImageError::UnsupportedFormat)?;
+        let reader = image::io::Reader::with_format(Cursor::new(bytes), format);
+
+        let (width, height) = reader.into_dimensions().map_err(ImageError::from)?;
+        if width > self.max_width || height > self.max_height {
+            return Err(ImageError::TooLarge(format!(
+                "image is {}x{}, the limit is {}x{}",
+                width, height, self.max_width, self.max_height
+            )));
+        }
+        if width as u64 * height as u64 > self.max_pixels {
+            return Err(ImageError::TooLarge(format!(
+                "image has {} pixels, the limit is {}",
+                width as u64 * height as u64,
+                self.max_pixels
+            )));
+        }
+
+        let frames = frame_count(format, bytes);
+        if frames > self.max_frames {
+            return Err(ImageError::TooLarge(format!(
+                "image has {} frames, the limit is {}",
+                frames, self.max_frames
+            )));
+        }
+
+        // the decoder enforces the same bounds in case the header check missed a trick
+        let mut reader = image::io::Reader::with_format(Cursor::new(bytes), format);
+        let mut limits = image::io::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        reader.limits(limits);
+
+        reader.decode().map_err(ImageError::from)
+    }
+}
+
+impl From<AssetError> for ImageError {
+    fn from(err: AssetError) -> Self {
+        ImageError::Asset(err)
+    }
+}
+
+impl From<BlockingError> for ImageError {
+    fn from(_: BlockingError) -> Self {
+        ImageError::Interrupted
+    }
+}
+
+impl From<image::ImageError> for ImageError {
+    fn from(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::Unsupported(_) => ImageError::UnsupportedFormat,
+            image::ImageError::Limits(err) => ImageError::TooLarge(err.to_string()),
+            err => ImageError::Decode(err),
+        }
+    }
+}
+
+/// Counts animation frames from the container structure alone. Formats that
+/// cannot animate, and anything the walk does not understand, count as one.
+fn frame_count(format: ImageFormat, bytes: &[u8]) -> u32 {
+    let frames = match format {
+        ImageFormat::Gif => gif_frames(bytes),
+        ImageFormat::Png => apng_frames(bytes),
+        ImageFormat::WebP => webp_frames(bytes),
+        _ => None,
+    };
+
+    frames.unwrap_or(1).max(1)
+}
+
+fn gif_frames(bytes: &[u8]) -> Option<u32> {
+    fn skip_sub_blocks(bytes: &[u8], mut pos: usize) -> Option<usize> {
+        loop {
+            let len = *bytes.get(pos)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    }
+    let colour_table = |packed: u8| match packed & 0x80 {
+        0 => 0,
+        _ => 3 << ((packed & 0x07) + 1),
+    };
+
+    // header and logical screen descriptor
+    let mut pos = 13 + colour_table(*bytes.get(10)?);
+    let mut frames = 0;
+
+    loop {
+        match *bytes.get(pos)? {
+            // extension: label, then data sub-blocks
+            0x21 => pos = skip_sub_blocks(bytes, pos + 2)?,
+            // image descriptor: position, size and packed fields, then lzw data
+            0x2c => {
+                frames += 1;
+                pos += 10 + colour_table(*bytes.get(pos + 9)?);
+                pos = skip_sub_blocks(bytes, pos + 1)?;
+            }
+            // trailer, or something we do not understand
+            _ => return Some(frames),
+        }
+    }
+}
+
+fn apng_frames(bytes: &[u8]) -> Option<u32> {
+    let mut pos = 8;
+
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let len = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+        match &header[4..] {
+            b"acTL" => {
+                let frames = bytes.get(pos + 8..pos + 12)?;
+                return Some(u32::from_be_bytes(frames.try_into().ok()?));
+            }
+            // the animation control chunk must come before the image data
+            b"IDAT" => return Some(1),
+            _ => pos += 12 + len,
+        }
+    }
+
+    None
+}
+
+fn webp_frames(bytes: &[u8]) -> Option<u32> {
+    let mut pos = 12;
+    let mut frames = 0;
+
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let len = u32::from_le_bytes(header[4..].try_into().ok()?) as usize;
+        if &header[..4] == b"ANMF" {
+            frames += 1;
+        }
+        pos += 8 + len + len % 2;
+    }
+
+    Some(frames)
+}
+
+/// Runs `f` over every uploaded file, keeping going past failures so each
+/// file gets its own result or error.
+fn per_file(
+    images: &[Bytes],
+    f: impl Fn(&Bytes) -> Result<serde_json::Value, ImageError>,
+) -> serde_json::Value {
+    images
+        .iter()
+        .map(|image| {
+            let mut result = match f(image) {
+                Ok(serde_json::Value::Object(result)) => result,
+                Ok(value) => [("result".to_string(), value)].into_iter().collect(),
+                Err(err) => [("error".to_string(), json!(err.to_string()))]
+                    .into_iter()
+                    .collect(),
+            };
+            result.insert("filename".to_string(), json!(image.file_name));
+
+            serde_json::Value::Object(result)
+        })
+        .collect()
+}
 
 #[get("/11/assets/{filename:.*}")]
-pub async fn day_11_image(path: web::Path<PathBuf>) -> impl Responder {
-    let asset_path = path.into_inner();
-    let asset_path = asset_path.as_path();
+pub async fn day_11_image(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, AssetError> {
+    let (asset_path, etag, content_type) = web::block(move || {
+        let asset_path = data.assets.resolve(&path.into_inner())?;
+        let etag = data.assets.etag(&asset_path)?;
+        let content_type = sniff_content_type(&asset_path)?;
+
+        Ok::<_, AssetError>((asset_path, etag, content_type))
+    })
+    .await??;
+
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+
+    let mut res = if not_modified {
+        HttpResponse::NotModified().finish()
+    } else {
+        NamedFile::open_async(&asset_path)
+            .await?
+            .use_etag(false)
+            .set_content_type(content_type)
+            .into_response(&req)
+    };
+
+    let headers = res.headers_mut();
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag).expect("hex etag is a valid header"),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(ASSET_CACHE_CONTROL),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    // uploaded svg or html must not run scripts on our origin
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("default-src 'none'; style-src 'unsafe-inline'; sandbox"),
+    );
 
-    NamedFile::open_async(format!("assets/{}", asset_path.display())).await
+    Ok(res)
+}
+
+#[get("/11/assets")]
+pub async fn day_11_list_assets(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, AssetError> {
+    data.assets.authorize(&req)?;
+
+    let assets = web::block(move || data.assets.list()).await??;
+
+    Ok(web::Json(json!(assets)))
+}
+
+#[put("/11/assets/{filename:.*}")]
+pub async fn day_11_upload_asset(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, AssetError> {
+    data.assets.authorize(&req)?;
+
+    let asset = web::block(move || data.assets.store(&path.into_inner(), &body)).await??;
+
+    Ok(HttpResponse::Created().json(asset))
+}
+
+#[delete("/11/assets/{filename:.*}")]
+pub async fn day_11_delete_asset(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, AssetError> {
+    data.assets.authorize(&req)?;
+
+    web::block(move || data.assets.remove(&path.into_inner())).await??;
+
+    Ok(HttpResponse::Ok())
 }
 
 #[derive(MultipartForm)]
 struct ImageForm {
-    image: Bytes,
+    image: Vec<Bytes>,
 }
 
-#[post("/11/red_pixels")]
-pub async fn day_11_red_pixels(MultipartForm(form): MultipartForm<ImageForm>) -> impl Responder {
-    let img = image::load_from_memory(&form.image.data).unwrap();
-
-    let magical_red = img
-        .pixels()
+fn count_magical_red(img: &DynamicImage) -> usize {
+    img.pixels()
         .filter(|(_x, _y, rgba)| {
             let [r, g, b, _a] = rgba.0;
 
             r as u16 > (g as u16 + b as u16)
         })
-        .count();
+        .count()
+}
+
+/// A single image answers with the bare count as before, several images with
+/// a json array holding a count or an error for each file.
+#[post("/11/red_pixels")]
+pub async fn day_11_red_pixels(
+    MultipartForm(form): MultipartForm<ImageForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ImageError> {
+    let counts = web::block(move || match form.image.as_slice() {
+        [] => Err(ImageError::MissingImage),
+        [image] => {
+            let img = data.image_limits.decode(&image.data)?;
+
+            Ok(json!(count_magical_red(&img)))
+        }
+        images => Ok(per_file(images, |image| {
+            let img = data.image_limits.decode(&image.data)?;
+
+            Ok(json!({ "count": count_magical_red(&img) }))
+        })),
+    })
+    .await??;
+
+    Ok(match counts {
+        serde_json::Value::Array(_) => HttpResponse::Ok().json(counts),
+        count => HttpResponse::Ok().body(count.to_string()),
+    })
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+/// Inclusive limits on one colour component, either side may be left open.
+#[derive(Clone, Copy, Default, Deserialize)]
+struct Bounds {
+    min: Option<f32>,
+    max: Option<f32>,
+}
+
+impl Bounds {
+    fn contains(&self, value: f32) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+
+    /// Hue ranges wrap around, so `min: 330, max: 30` covers the reds.
+    fn contains_hue(&self, hue: f32) -> bool {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min > max => hue >= min || hue <= max,
+            _ => self.contains(hue),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "space", rename_all = "lowercase")]
+enum ColourTest {
+    /// Channels from 0 to 255. A `dominant` channel must be brighter than
+    /// the other two added together, which is how "magical red" is defined.
+    Rgb {
+        dominant: Option<Channel>,
+        #[serde(default)]
+        red: Bounds,
+        #[serde(default)]
+        green: Bounds,
+        #[serde(default)]
+        blue: Bounds,
+    },
+    /// Hue in degrees, saturation and value from 0 to 1.
+    Hsv {
+        #[serde(default)]
+        hue: Bounds,
+        #[serde(default)]
+        saturation: Bounds,
+        #[serde(default)]
+        value: Bounds,
+    },
+    /// CIE L*a*b* under D65, lightness from 0 to 100.
+    Lab {
+        #[serde(default)]
+        lightness: Bounds,
+        #[serde(default)]
+        a: Bounds,
+        #[serde(default)]
+        b: Bounds,
+    },
+}
+
+#[derive(Deserialize)]
+struct ColourRule {
+    name: String,
+    #[serde(flatten)]
+    test: ColourTest,
+}
+
+impl ColourRule {
+    fn magical_red() -> Self {
+        ColourRule {
+            name: "magical_red".to_string(),
+            test: ColourTest::magical_red(),
+        }
+    }
+}
+
+impl ColourTest {
+    fn magical_red() -> Self {
+        ColourTest::Rgb {
+            dominant: Some(Channel::Red),
+            red: Bounds::default(),
+            green: Bounds::default(),
+            blue: Bounds::default(),
+        }
+    }
+
+    fn matches(&self, pixel: Rgb<u8>) -> bool {
+        match self {
+            ColourTest::Rgb {
+                dominant,
+                red,
+                green,
+                blue,
+            } => {
+                let [r, g, b] = pixel.0.map(u16::from);
+                let dominant = match dominant {
+                    Some(Channel::Red) => r > g + b,
+                    Some(Channel::Green) => g > r + b,
+                    Some(Channel::Blue) => b > r + g,
+                    None => true,
+                };
+
+                dominant
+                    && red.contains(r as f32)
+                    && green.contains(g as f32)
+                    && blue.contains(b as f32)
+            }
+            ColourTest::Hsv {
+                hue,
+                saturation,
+                value,
+            } => {
+                let [h, s, v] = to_hsv(pixel);
+
+                hue.contains_hue(h) && saturation.contains(s) && value.contains(v)
+            }
+            ColourTest::Lab { lightness, a, b } => {
+                let [l, lab_a, lab_b] = to_lab(pixel);
+
+                lightness.contains(l) && a.contains(lab_a) && b.contains(lab_b)
+            }
+        }
+    }
+}
+
+fn to_hsv(pixel: Rgb<u8>) -> [f32; 3] {
+    let [r, g, b] = pixel.0.map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+
+    [hue, saturation, max]
+}
+
+fn to_lab(pixel: Rgb<u8>) -> [f32; 3] {
+    // linearised srgb for every channel value, the gamma curve is the slow part
+    static LINEAR: OnceLock<[f32; 256]> = OnceLock::new();
+    let linear = LINEAR.get_or_init(|| {
+        std::array::from_fn(|c| {
+            let c = c as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    });
+    let [r, g, b] = pixel.0.map(|c| linear[c as usize]);
+
+    // xyz relative to the d65 white point
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// pixels sampled for the palette, evenly spread over the image
+const PALETTE_SAMPLE: usize = 20_000;
+const KMEANS_ITERATIONS: usize = 25;
+
+#[derive(Serialize)]
+struct PaletteColour {
+    hex: String,
+    rgb: [u8; 3],
+    percentage: f64,
+}
+
+fn lab_distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// The `size` most common colours, found by k-means in Lab space over a
+/// sample of the pixels. Seeded so the same image always gives the same palette.
+fn dominant_palette(img: &RgbImage, size: usize) -> Vec<PaletteColour> {
+    let pixel_count = img.pixels().len();
+    if pixel_count == 0 {
+        return Vec::new();
+    }
+
+    let step = pixel_count.div_ceil(PALETTE_SAMPLE);
+    let sample: Vec<(Rgb<u8>, [f32; 3])> = img
+        .pixels()
+        .step_by(step)
+        .map(|pixel| (*pixel, to_lab(*pixel)))
+        .collect();
+
+    // k-means++ seeding, stopping early when every pixel already sits on a centroid
+    let mut rng = StdRng::seed_from_u64(11);
+    let mut centroids = vec![sample[rng.gen_range(0..sample.len())].1];
+    let mut nearest: Vec<f32> = sample
+        .iter()
+        .map(|(_, lab)| lab_distance(lab, &centroids[0]))
+        .collect();
+    while centroids.len() < size {
+        let total: f32 = nearest.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+
+        let mut target = rng.gen_range(0.0..total);
+        let chosen = nearest
+            .iter()
+            .position(|distance| {
+                target -= distance;
+                target < 0.0
+            })
+            .unwrap_or(sample.len() - 1);
+        let centroid = sample[chosen].1;
+
+        for ((_, lab), distance) in sample.iter().zip(nearest.iter_mut()) {
+            *distance = distance.min(lab_distance(lab, &centroid));
+        }
+        centroids.push(centroid);
+    }
+
+    let closest = |lab: &[f32; 3], centroids: &[[f32; 3]]| {
+        centroids
+            .iter()
+            .map(|centroid| lab_distance(lab, centroid))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(cluster, _)| cluster)
+    };
+
+    let mut assignment = vec![usize::MAX; sample.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for ((_, lab), cluster) in sample.iter().zip(assignment.iter_mut()) {
+            let best = closest(lab, &centroids);
+            changed |= best != *cluster;
+            *cluster = best;
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![([0.0f32; 3], 0usize); centroids.len()];
+        for ((_, lab), cluster) in sample.iter().zip(&assignment) {
+            let (sum, count) = &mut sums[*cluster];
+            sum.iter_mut().zip(lab).for_each(|(s, c)| *s += c);
+            *count += 1;
+        }
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            if count > 0 {
+                *centroid = sum.map(|s| s / count as f32);
+            }
+        }
+    }
+
+    // report each cluster as the average srgb colour of its members
+    let mut clusters = vec![([0u64; 3], 0usize); centroids.len()];
+    for ((pixel, _), cluster) in sample.iter().zip(&assignment) {
+        let (sum, count) = &mut clusters[*cluster];
+        sum.iter_mut()
+            .zip(pixel.0)
+            .for_each(|(s, c)| *s += c as u64);
+        *count += 1;
+    }
+    clusters.retain(|(_, count)| *count > 0);
+    clusters.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    clusters
+        .into_iter()
+        .map(|(sum, count)| {
+            let rgb = sum.map(|s| (s as f64 / count as f64).round() as u8);
+            PaletteColour {
+                hex: format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]),
+                rgb,
+                percentage: count as f64 * 100.0 / sample.len() as f64,
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Histogram {
+    bins: usize,
+    red: Vec<u64>,
+    green: Vec<u64>,
+    blue: Vec<u64>,
+}
+
+#[derive(MultipartForm)]
+struct AnalyzeForm {
+    image: Vec<Bytes>,
+    /// Json array of colour rules, defaults to counting magical red.
+    rules: Option<Text<String>>,
+}
+
+fn default_palette_size() -> usize {
+    5
+}
+
+fn default_histogram_bins() -> usize {
+    16
+}
+
+#[derive(Deserialize)]
+struct AnalyzeQuery {
+    #[serde(default = "default_palette_size")]
+    palette: usize,
+    #[serde(default = "default_histogram_bins")]
+    bins: usize,
+}
+
+#[post("/11/analyze")]
+pub async fn day_11_analyze(
+    MultipartForm(form): MultipartForm<AnalyzeForm>,
+    query: web::Query<AnalyzeQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ImageError> {
+    if !(1..=16).contains(&query.palette) {
+        return Err(ImageError::InvalidParameter(
+            "palette must be between 1 and 16".to_string(),
+        ));
+    }
+    if !(1..=256).contains(&query.bins) {
+        return Err(ImageError::InvalidParameter(
+            "bins must be between 1 and 256".to_string(),
+        ));
+    }
+
+    let rules: Vec<ColourRule> = match form.rules {
+        Some(rules) => serde_json::from_str(&rules).map_err(ImageError::InvalidRules)?,
+        None => vec![ColourRule::magical_red()],
+    };
+
+    let query = query.into_inner();
+    let result = web::block(move || {
+        let analyze = |image: &Bytes| {
+            let img = data.image_limits.decode(&image.data)?;
+            analyze_image(&img, &rules, &query)
+        };
+
+        match form.image.as_slice() {
+            [] => Err(ImageError::MissingImage),
+            [image] => analyze(image),
+            images => Ok(per_file(images, analyze)),
+        }
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+fn analyze_image(
+    img: &DynamicImage,
+    rules: &[ColourRule],
+    query: &AnalyzeQuery,
+) -> Result<serde_json::Value, ImageError> {
+    let img = img.to_rgb8();
+
+    let mut counts = vec![0u64; rules.len()];
+    let mut histogram = Histogram {
+        bins: query.bins,
+        red: vec![0; query.bins],
+        green: vec![0; query.bins],
+        blue: vec![0; query.bins],
+    };
+    for pixel in img.pixels() {
+        for (rule, count) in rules.iter().zip(counts.iter_mut()) {
+            if rule.test.matches(*pixel) {
+                *count += 1;
+            }
+        }
+
+        let [r, g, b] = pixel.0.map(|c| c as usize * query.bins / 256);
+        histogram.red[r] += 1;
+        histogram.green[g] += 1;
+        histogram.blue[b] += 1;
+    }
+
+    let pixels = img.pixels().len() as u64;
+    let percentage = |count: u64| match pixels {
+        0 => 0.0,
+        _ => count as f64 * 100.0 / pixels as f64,
+    };
+
+    Ok(json!({
+        "width": img.width(),
+        "height": img.height(),
+        "pixels": pixels,
+        "rules": rules.iter().zip(counts).map(|(rule, count)| json!({
+            "name": rule.name,
+            "count": count,
+            "percentage": percentage(count),
+        })).collect::<Vec<_>>(),
+        "histogram": histogram,
+        "palette": dominant_palette(&img, query.palette),
+    }))
+}
+
+const MAX_PIPELINE_STEPS: usize = 32;
+const MAX_OUTPUT_DIMENSION: u32 = 8192;
+const MAX_BLUR_SIGMA: f32 = 50.0;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FlipDirection {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    /// Keeps the aspect ratio inside the given box unless `exact` is set,
+    /// a missing side is derived from the other one.
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+        #[serde(default)]
+        exact: bool,
+    },
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Clockwise, in multiples of 90 degrees.
+    Rotate {
+        degrees: u32,
+    },
+    Flip {
+        direction: FlipDirection,
+    },
+    Grayscale,
+    Blur {
+        sigma: f32,
+    },
+    /// Added to every channel, from -255 to 255.
+    Brightness {
+        value: i32,
+    },
+    /// Percentage change, negative values reduce contrast.
+    Contrast {
+        value: f32,
+    },
+}
+
+/// Parses the compact query form, for example `resize:200,` or `crop:0,0,64,64`.
+impl FromStr for Operation {
+    type Err = String;
+
+    fn from_str(step: &str) -> Result<Self, Self::Err> {
+        let (name, args) = step.split_once(':').unwrap_or((step, ""));
+        let args: Vec<&str> = match args {
+            "" => Vec::new(),
+            args => args.split(',').map(str::trim).collect(),
+        };
+
+        fn arg<T: FromStr>(step: &str, value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid argument {:?} in step {:?}", value, step))
+        }
+        let optional = |value: &str| match value {
+            "" => Ok(None),
+            value => arg(step, value).map(Some),
+        };
+
+        let operation = match (name.trim(), args.as_slice()) {
+            ("resize", [width, height]) => Operation::Resize {
+                width: optional(width)?,
+                height: optional(height)?,
+                exact: false,
+            },
+            ("resize_exact", [width, height]) => Operation::Resize {
+                width: Some(arg(step, width)?),
+                height: Some(arg(step, height)?),
+                exact: true,
+            },
+            ("crop", [x, y, width, height]) => Operation::Crop {
+                x: arg(step, x)?,
+                y: arg(step, y)?,
+                width: arg(step, width)?,
+                height: arg(step, height)?,
+            },
+            ("rotate", [degrees]) => Operation::Rotate {
+                degrees: arg(step, degrees)?,
+            },
+            ("flip", ["horizontal"]) => Operation::Flip {
+                direction: FlipDirection::Horizontal,
+            },
+            ("flip", ["vertical"]) => Operation::Flip {
+                direction: FlipDirection::Vertical,
+            },
+            ("grayscale", []) => Operation::Grayscale,
+            ("blur", [sigma]) => Operation::Blur {
+                sigma: arg(step, sigma)?,
+            },
+            ("brightness", [value]) => Operation::Brightness {
+                value: arg(step, value)?,
+            },
+            ("contrast", [value]) => Operation::Contrast {
+                value: arg(step, value)?,
+            },
+            _ => return Err(format!("unknown pipeline step {:?}", step)),
+        };
+
+        Ok(operation)
+    }
+}
+
+impl Operation {
+    fn apply(&self, img: DynamicImage) -> Result<DynamicImage, String> {
+        let check_size = |width: u32, height: u32| {
+            if width == 0 || height == 0 {
+                Err("resulting image would be empty".to_string())
+            } else if width > MAX_OUTPUT_DIMENSION || height > MAX_OUTPUT_DIMENSION {
+                Err(format!(
+                    "resulting image would exceed {} pixels per side",
+                    MAX_OUTPUT_DIMENSION
+                ))
+            } else {
+                Ok(())
+            }
+        };
+
+        let img = match *self {
+            Operation::Resize {
+                width,
+                height,
+                exact,
+            } => {
+                let (current_width, current_height) = img.dimensions();
+                let (width, height) = match (width, height) {
+                    (None, None) => return Err("resize needs a width or a height".to_string()),
+                    (Some(width), Some(height)) => (width, height),
+                    (Some(width), None) => {
+                        let scaled = current_height as u64 * width as u64 / current_width as u64;
+                        (width, scaled.max(1) as u32)
+                    }
+                    (None, Some(height)) => {
+                        let scaled = current_width as u64 * height as u64 / current_height as u64;
+                        (scaled.max(1) as u32, height)
+                    }
+                };
+                check_size(width, height)?;
+
+                if exact {
+                    img.resize_exact(width, height, FilterType::Lanczos3)
+                } else {
+                    img.resize(width, height, FilterType::Lanczos3)
+                }
+            }
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let inside = x
+                    .checked_add(width)
+                    .is_some_and(|right| right <= img.width())
+                    && y.checked_add(height)
+                        .is_some_and(|bottom| bottom <= img.height());
+                if !inside {
+                    return Err(format!(
+                        "crop does not fit inside the {}x{} image",
+                        img.width(),
+                        img.height()
+                    ));
+                }
+                check_size(width, height)?;
+
+                img.crop_imm(x, y, width, height)
+            }
+            Operation::Rotate { degrees } => match degrees % 360 {
+                0 => img,
+                90 => img.rotate90(),
+                180 => img.rotate180(),
+                270 => img.rotate270(),
+                _ => return Err("rotation must be a multiple of 90 degrees".to_string()),
+            },
+            Operation::Flip {
+                direction: FlipDirection::Horizontal,
+            } => img.fliph(),
+            Operation::Flip {
+                direction: FlipDirection::Vertical,
+            } => img.flipv(),
+            Operation::Grayscale => img.grayscale(),
+            Operation::Blur { sigma } => {
+                if !(sigma > 0.0 && sigma <= MAX_BLUR_SIGMA) {
+                    return Err(format!(
+                        "blur sigma must be above 0 and at most {}",
+                        MAX_BLUR_SIGMA
+                    ));
+                }
+                img.blur(sigma)
+            }
+            Operation::Brightness { value } => {
+                if !(-255..=255).contains(&value) {
+                    return Err("brightness must be between -255 and 255".to_string());
+                }
+                img.brighten(value)
+            }
+            Operation::Contrast { value } => {
+                if !value.is_finite() {
+                    return Err("contrast must be a number".to_string());
+                }
+                img.adjust_contrast(value)
+            }
+        };
+
+        Ok(img)
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg,
+    Gif,
+}
+
+// parsed by hand so an unsupported format such as webp gets a clear error
+impl FromStr for OutputFormat {
+    type Err = ImageError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "gif" => Ok(OutputFormat::Gif),
+            _ => Err(ImageError::UnsupportedOutput(name.to_string())),
+        }
+    }
+}
+
+impl OutputFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+
+    fn encode(self, img: &DynamicImage, quality: u8) -> Result<Vec<u8>, ImageError> {
+        let mut out = Cursor::new(Vec::new());
+
+        // jpeg has no alpha channel and the gif encoder wants rgba frames
+        match self {
+            OutputFormat::Png => img.write_to(&mut out, ImageOutputFormat::Png),
+            OutputFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_to(&mut out, ImageOutputFormat::Jpeg(quality)),
+            OutputFormat::Gif => {
+                DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut out, ImageOutputFormat::Gif)
+            }
+        }
+        .map_err(ImageError::Encode)?;
+
+        Ok(out.into_inner())
+    }
+}
+
+// total size of encoded results kept by the transform cache
+const TRANSFORM_CACHE_BYTES: usize = 64 << 20;
+
+#[derive(Default)]
+struct CacheEntries {
+    images: HashMap<String, web::Bytes>,
+    // insertion order, the oldest entry is evicted first
+    order: VecDeque<String>,
+    size: usize,
+}
+
+/// Encoded transform results keyed by the input's SHA-256 and the pipeline.
+#[derive(Default)]
+pub struct TransformCache {
+    entries: Mutex<CacheEntries>,
+}
+
+impl TransformCache {
+    fn get(&self, key: &str) -> Option<web::Bytes> {
+        self.entries.lock().unwrap().images.get(key).cloned()
+    }
+
+    fn insert(&self, key: String, image: web::Bytes) {
+        if image.len() > TRANSFORM_CACHE_BYTES {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.images.contains_key(&key) {
+            return;
+        }
+        while entries.size + image.len() > TRANSFORM_CACHE_BYTES {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = entries.images.remove(&oldest) {
+                entries.size -= evicted.len();
+            }
+        }
+
+        entries.size += image.len();
+        entries.order.push_back(key.clone());
+        entries.images.insert(key, image);
+    }
+}
+
+#[derive(MultipartForm)]
+struct TransformForm {
+    image: Bytes,
+    /// Json array of operations, as an alternative to the `ops` query.
+    pipeline: Option<Text<String>>,
+}
+
+fn default_quality() -> u8 {
+    85
+}
+
+#[derive(Deserialize)]
+struct TransformQuery {
+    /// Steps separated by `|`, for example `resize:200,|rotate:90|grayscale`.
+    ops: Option<String>,
+    /// png, jpeg or gif, png when left out.
+    format: Option<String>,
+    /// Jpeg quality from 1 to 100.
+    #[serde(default = "default_quality")]
+    quality: u8,
+}
+
+#[post("/11/transform")]
+pub async fn day_11_transform(
+    MultipartForm(form): MultipartForm<TransformForm>,
+    query: web::Query<TransformQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ImageError> {
+    let query = query.into_inner();
+    if !(1..=100).contains(&query.quality) {
+        return Err(ImageError::InvalidParameter(
+            "quality must be between 1 and 100".to_string(),
+        ));
+    }
+    let format: OutputFormat = match query.format {
+        Some(format) => format.parse()?,
+        None => OutputFormat::default(),
+    };
+
+    let pipeline: Vec<Operation> = match (query.ops, form.pipeline) {
+        (Some(_), Some(_)) => {
+            return Err(ImageError::InvalidParameter(
+                "give the pipeline either as ops or as a pipeline field, not both".to_string(),
+            ))
+        }
+        (Some(ops), None) => ops
+            .split('|')
+            .filter(|step| !step.trim().is_empty())
+            .map(Operation::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(ImageError::InvalidParameter)?,
+        (None, Some(pipeline)) => serde_json::from_str(&pipeline)
+            .map_err(|err| ImageError::InvalidParameter(format!("invalid pipeline: {}", err)))?,
+        (None, None) => Vec::new(),
+    };
+    if pipeline.len() > MAX_PIPELINE_STEPS {
+        return Err(ImageError::InvalidParameter(format!(
+            "pipelines are limited to {} steps",
+            MAX_PIPELINE_STEPS
+        )));
+    }
+
+    // the quality only changes jpeg output, so other formats share one entry
+    let quality = match format {
+        OutputFormat::Jpeg => query.quality,
+        _ => 0,
+    };
+    let key = format!(
+        "{}/{}/{}/{}",
+        hex::encode(Sha256::digest(&form.image.data)),
+        json!(format),
+        quality,
+        json!(pipeline)
+    );
+
+    let content_type = format.content_type();
+    if let Some(cached) = data.transforms.get(&key) {
+        return Ok(HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("X-Cache", "hit"))
+            .body(cached));
+    }
+
+    let encoded = {
+        let data = data.clone();
+        web::block(move || {
+            let mut img = data.image_limits.decode(&form.image.data)?;
+            for operation in &pipeline {
+                img = operation.apply(img).map_err(ImageError::InvalidParameter)?;
+            }
+
+            format.encode(&img, quality)
+        })
+        .await??
+    };
+    let encoded = web::Bytes::from(encoded);
+    data.transforms.insert(key, encoded.clone());
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(("X-Cache", "miss"))
+        .body(encoded))
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MaskStyle {
+    /// White where the rule matches, black elsewhere.
+    #[default]
+    Mask,
+    /// The original image with matching pixels tinted.
+    Overlay,
+}
+
+#[derive(MultipartForm)]
+struct MaskForm {
+    image: Bytes,
+    /// Json colour rule without a name, defaults to magical red.
+    rule: Option<Text<String>>,
+}
+
+fn default_overlay_colour() -> String {
+    "00ffff".to_string()
+}
+
+fn default_overlay_opacity() -> f32 {
+    0.6
+}
+
+#[derive(Deserialize)]
+struct MaskQuery {
+    #[serde(default)]
+    style: MaskStyle,
+    /// Hex rgb used to tint matches in an overlay.
+    #[serde(default = "default_overlay_colour")]
+    colour: String,
+    #[serde(default = "default_overlay_opacity")]
+    opacity: f32,
+}
+
+/// Renders the pixels `/11/red_pixels` counts as a png. The count itself is
+/// sent in the `X-Pixel-Count` header.
+#[post("/11/red_pixels/mask")]
+pub async fn day_11_red_pixels_mask(
+    MultipartForm(form): MultipartForm<MaskForm>,
+    query: web::Query<MaskQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ImageError> {
+    let tint = u32::from_str_radix(query.colour.trim_start_matches('#'), 16)
+        .ok()
+        .filter(|_| query.colour.trim_start_matches('#').len() == 6)
+        .map(|rgb| rgb.to_be_bytes())
+        .ok_or_else(|| {
+            ImageError::InvalidParameter("colour must be a six digit hex rgb value".to_string())
+        })?;
+    if !(0.0..=1.0).contains(&query.opacity) {
+        return Err(ImageError::InvalidParameter(
+            "opacity must be between 0 and 1".to_string(),
+        ));
+    }
+
+    let rule = match form.rule {
+        Some(rule) => serde_json::from_str(&rule).map_err(ImageError::InvalidRules)?,
+        None => ColourTest::magical_red(),
+    };
+
+    let (style, opacity) = (query.style, query.opacity);
+    let (count, png) = web::block(move || {
+        let img = data.image_limits.decode(&form.image.data)?.to_rgba8();
+        let matches = |pixel: &Rgba<u8>| {
+            let [r, g, b, _a] = pixel.0;
+            rule.matches(Rgb([r, g, b]))
+        };
+
+        let mut count = 0u64;
+        let rendered = match style {
+            MaskStyle::Mask => {
+                DynamicImage::ImageLuma8(GrayImage::from_fn(img.width(), img.height(), |x, y| {
+                    if matches(img.get_pixel(x, y)) {
+                        count += 1;
+                        Luma([255])
+                    } else {
+                        Luma([0])
+                    }
+                }))
+            }
+            MaskStyle::Overlay => {
+                let mut overlay = img.clone();
+                for pixel in overlay.pixels_mut() {
+                    if matches(pixel) {
+                        count += 1;
+                        for (channel, tint) in pixel.0.iter_mut().zip(&tint[1..]) {
+                            let blended =
+                                *channel as f32 * (1.0 - opacity) + *tint as f32 * opacity;
+                            *channel = blended.round() as u8;
+                        }
+                        // keep highlighted pixels visible even where the original is transparent
+                        pixel.0[3] = pixel.0[3].max((opacity * 255.0).round() as u8);
+                    }
+                }
+                DynamicImage::ImageRgba8(overlay)
+            }
+        };
+
+        Ok::<_, ImageError>((count, OutputFormat::Png.encode(&rendered, 0)?))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok()
+        .content_type(OutputFormat::Png.content_type())
+        .insert_header(("X-Pixel-Count", count.to_string()))
+        .body(png))
+}
+
+/// 64-bit perceptual hashes, bits set row by row from the most significant.
+#[derive(Clone, Copy)]
+struct ImageHashes {
+    average: u64,
+    difference: u64,
+    perceptual: u64,
+}
+
+impl ImageHashes {
+    fn of(img: &DynamicImage) -> Self {
+        let gray = |width, height| {
+            img.resize_exact(width, height, FilterType::Triangle)
+                .to_luma8()
+        };
+        let bits = |bits: &mut dyn Iterator<Item = bool>| {
+            bits.fold(0u64, |hash, bit| (hash << 1) | bit as u64)
+        };
+
+        // average: brighter than the mean of an 8x8 thumbnail
+        let small = gray(8, 8);
+        let mean = small.pixels().map(|p| p[0] as u32).sum::<u32>() / 64;
+        let average = bits(&mut small.pixels().map(|p| p[0] as u32 > mean));
+
+        // difference: brighter than the right hand neighbour in a 9x8 thumbnail
+        let wide = gray(9, 8);
+        let difference = bits(
+            &mut (0..8)
+                .flat_map(|y| (0..8).map(move |x| (x, y)))
+                .map(|(x, y)| wide.get_pixel(x, y)[0] > wide.get_pixel(x + 1, y)[0]),
+        );
+
+        // perceptual: lowest 8x8 frequencies of a 32x32 dct, against their median
+        let large = gray(32, 32);
+        let coefficients = low_frequencies(&large);
+        let mut sorted = coefficients[1..].to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let median = (sorted[31] + sorted[32]) / 2.0;
+        let perceptual = bits(&mut coefficients.iter().map(|c| *c > median));
+
+        ImageHashes {
+            average,
+            difference,
+            perceptual,
+        }
+    }
+
+    fn distance(&self, other: &ImageHashes) -> serde_json::Value {
+        json!({
+            "ahash": (self.average ^ other.average).count_ones(),
+            "dhash": (self.difference ^ other.difference).count_ones(),
+            "phash": (self.perceptual ^ other.perceptual).count_ones(),
+        })
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        json!({
+            "ahash": format!("{:016x}", self.average),
+            "dhash": format!("{:016x}", self.difference),
+            "phash": format!("{:016x}", self.perceptual),
+        })
+    }
+}
+
+/// The top-left 8x8 block of the 2d dct-ii of a 32x32 image, row by row.
+fn low_frequencies(img: &image::GrayImage) -> Vec<f64> {
+    const N: usize = 32;
+    const KEEP: usize = 8;
+
+    let basis: Vec<[f64; N]> = (0..KEEP)
+        .map(|k| {
+            std::array::from_fn(|n| {
+                (std::f64::consts::PI / N as f64 * (n as f64 + 0.5) * k as f64).cos()
+            })
+        })
+        .collect();
+
+    // transform rows, then the columns of what is left
+    let rows: Vec<[f64; KEEP]> = (0..N)
+        .map(|y| {
+            std::array::from_fn(|k| {
+                (0..N)
+                    .map(|x| img.get_pixel(x as u32, y as u32)[0] as f64 * basis[k][x])
+                    .sum()
+            })
+        })
+        .collect();
+
+    (0..KEEP)
+        .flat_map(|v| (0..KEEP).map(move |u| (u, v)))
+        .map(|(u, v)| (0..N).map(|y| rows[y][u] * basis[v][y]).sum())
+        .collect()
+}
+
+// largest side the images are brought to before ssim and the diff
+const COMPARE_DIMENSION: u32 = 1024;
+
+/// Mean structural similarity over 8x8 windows with a stride of 4.
+fn structural_similarity(a: &image::GrayImage, b: &image::GrayImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const STRIDE: u32 = 4;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    let window = WINDOW.min(width).min(height);
+    let starts = |len: u32| (0..=len - window).step_by(STRIDE as usize);
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y0 in starts(height) {
+        for x0 in starts(width) {
+            let pairs = (y0..y0 + window).flat_map(|y| {
+                (x0..x0 + window)
+                    .map(move |x| (a.get_pixel(x, y)[0] as f64, b.get_pixel(x, y)[0] as f64))
+            });
+            let n = (window * window) as f64;
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for (pa, pb) in pairs {
+                sa += pa;
+                sb += pb;
+                saa += pa * pa;
+                sbb += pb * pb;
+                sab += pa * pb;
+            }
+
+            let (mean_a, mean_b) = (sa / n, sb / n);
+            let var_a = saa / n - mean_a * mean_a;
+            let var_b = sbb / n - mean_b * mean_b;
+            let covariance = sab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+#[derive(MultipartForm)]
+struct CompareForm {
+    image: Vec<Bytes>,
+}
+
+/// Compares exactly two `image` parts. The second image is scaled to the
+/// first one's size before ssim and the diff are computed.
+#[post("/11/compare")]
+pub async fn day_11_compare(
+    MultipartForm(form): MultipartForm<CompareForm>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ImageError> {
+    let [first, second] = form.image.as_slice() else {
+        return Err(ImageError::InvalidParameter(
+            "compare needs exactly two image parts".to_string(),
+        ));
+    };
+    let (first, second) = (first.data.clone(), second.data.clone());
+    let result = web::block(move || {
+        let first = data.image_limits.decode(&first)?;
+        let second = data.image_limits.decode(&second)?;
+
+        let first_hashes = ImageHashes::of(&first);
+        let second_hashes = ImageHashes::of(&second);
+
+        let first = if first.width().max(first.height()) > COMPARE_DIMENSION {
+            first.resize(COMPARE_DIMENSION, COMPARE_DIMENSION, FilterType::Triangle)
+        } else {
+            first
+        };
+        let (width, height) = first.dimensions();
+        let second = second.resize_exact(width, height, FilterType::Triangle);
+
+        let ssim = structural_similarity(&first.to_luma8(), &second.to_luma8());
+
+        let (first, second) = (first.to_rgba8(), second.to_rgba8());
+        let diff = image::RgbaImage::from_fn(width, height, |x, y| {
+            let (a, b) = (first.get_pixel(x, y).0, second.get_pixel(x, y).0);
+            Rgba([
+                a[0].abs_diff(b[0]),
+                a[1].abs_diff(b[1]),
+                a[2].abs_diff(b[2]),
+                255,
+            ])
+        });
+        let diff = OutputFormat::Png.encode(&DynamicImage::ImageRgba8(diff), 0)?;
+
+        Ok::<_, ImageError>(json!({
+            "hashes": [first_hashes.to_json(), second_hashes.to_json()],
+            "distance": first_hashes.distance(&second_hashes),
+            "ssim": ssim,
+            "compared_at": [width, height],
+            "diff": format!("data:image/png;base64,{}", STANDARD.encode(diff)),
+        }))
+    })
+    .await??;
+
+    Ok(web::Json(result))
+}
+
+/// Hashes of asset files keyed by their content etag, so unchanged assets
+/// are not decoded again when the index is rebuilt.
+#[derive(Default)]
+pub struct HashIndex {
+    hashes: Mutex<HashMap<String, ImageHashes>>,
+}
+
+fn default_duplicate_threshold() -> u32 {
+    8
+}
+
+#[derive(Deserialize)]
+struct HashQuery {
+    /// Largest phash distance reported as a near duplicate.
+    #[serde(default = "default_duplicate_threshold")]
+    threshold: u32,
+}
+
+#[get("/11/hash")]
+pub async fn day_11_hash(
+    req: HttpRequest,
+    query: web::Query<HashQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ImageError> {
+    data.assets.authorize(&req)?;
+
+    let threshold = query.threshold;
+    let result = web::block(move || {
+        let images: Vec<AssetInfo> = data
+            .assets
+            .list()?
+            .into_iter()
+            .filter(|asset| asset.content_type.starts_with("image/"))
+            .collect();
+
+        let mut entries = Vec::new();
+        let mut hashed: Vec<(&str, ImageHashes)> = Vec::new();
+        for asset in &images {
+            let cached = data.hashes.hashes.lock().unwrap().get(&asset.etag).copied();
+            let hashes = match cached {
+                Some(hashes) => Ok(hashes),
+                None => data
+                    .assets
+                    .resolve(&asset.path)
+                    .map_err(ImageError::from)
+                    .and_then(|path| {
+                        std::fs::read(path).map_err(|err| AssetError::from(err).into())
+                    })
+                    .and_then(|bytes| data.image_limits.decode(&bytes))
+                    .map(|img| ImageHashes::of(&img)),
+            };
+
+            match hashes {
+                Ok(hashes) => {
+                    data.hashes
+                        .hashes
+                        .lock()
+                        .unwrap()
+                        .insert(asset.etag.clone(), hashes);
+                    hashed.push((&asset.path, hashes));
+
+                    let mut entry = hashes.to_json();
+                    entry["path"] = json!(asset.path);
+                    entries.push(entry);
+                }
+                Err(err) => entries.push(json!({ "path": asset.path, "error": err.to_string() })),
+            }
+        }
+
+        // forget assets that have since been replaced or removed
+        let current: HashSet<&str> = images.iter().map(|asset| asset.etag.as_str()).collect();
+        data.hashes
+            .hashes
+            .lock()
+            .unwrap()
+            .retain(|etag, _| current.contains(etag.as_str()));
+
+        let mut duplicates = Vec::new();
+        for (i, (first, first_hashes)) in hashed.iter().enumerate() {
+            for (second, second_hashes) in &hashed[i + 1..] {
+                let distance = (first_hashes.perceptual ^ second_hashes.perceptual).count_ones();
+                if distance <= threshold {
+                    duplicates.push((
+                        distance,
+                        json!({
+                            "first": first,
+                            "second": second,
+                            "distance": first_hashes.distance(second_hashes),
+                        }),
+                    ));
+                }
+            }
+        }
+        duplicates.sort_by_key(|(distance, _)| *distance);
+
+        Ok::<_, ImageError>(json!({
+            "assets": entries,
+            "duplicates": duplicates.into_iter().map(|(_, pair)| pair).collect::<Vec<_>>(),
+        }))
+    })
+    .await??;
+
+    Ok(web::Json(result))
+}
+
+/// Applies a tiff orientation tag so the pixels read the right way up once
+/// the tag itself is gone.
+fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+fn icc_profile(format: ImageFormat, bytes: &[u8]) -> Option<Vec<u8>> {
+    use image::{
+        codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder},
+        ImageDecoder,
+    };
+
+    let cursor = Cursor::new(bytes);
+    match format {
+        ImageFormat::Png => PngDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Jpeg => JpegDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::WebP => WebPDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Tiff => TiffDecoder::new(cursor).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+#[derive(MultipartForm)]
+struct MetadataForm {
+    image: Bytes,
+}
+
+fn default_strip_quality() -> u8 {
+    90
+}
+
+#[derive(Deserialize)]
+struct MetadataQuery {
+    /// Return the image re-encoded without any metadata instead of the report.
+    /// Animated uploads are refused rather than cut down to one frame.
+    #[serde(default)]
+    strip: bool,
+    /// Output format when stripping, png, jpeg or gif. Defaults to the
+    /// upload's own where possible.
+    format: Option<String>,
+    #[serde(default = "default_strip_quality")]
+    quality: u8,
+}
+
+#[post("/11/metadata")]
+pub async fn day_11_metadata(
+    MultipartForm(form): MultipartForm<MetadataForm>,
+    query: web::Query<MetadataQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ImageError> {
+    if query.strip && !(1..=100).contains(&query.quality) {
+        return Err(ImageError::InvalidParameter(
+            "quality must be between 1 and 100".to_string(),
+        ));
+    }
+    let strip_format: Option<OutputFormat> = query.format.as_deref().map(str::parse).transpose()?;
+
+    // responses are not Send, so the block gives back a content type and body
+    let query = query.into_inner();
+    let (content_type, body) = web::block(move || -> Result<_, ImageError> {
+        let bytes = &form.image.data[..];
+        let img = data.image_limits.decode(bytes)?;
+        let format = image::guess_format(bytes).map_err(|_| ImageError::UnsupportedFormat)?;
+        let exif = exif::find(format, bytes).and_then(exif::parse);
+
+        if query.strip {
+            // re-encoding from the decoded pixels leaves every metadata block
+            // behind, but keeps only the first frame of an animation
+            let frames = frame_count(format, bytes);
+            if frames > 1 {
+                return Err(ImageError::Animated(frames));
+            }
+            let orientation = exif.as_ref().and_then(|exif| exif.orientation);
+            let img = apply_orientation(img, orientation.unwrap_or(1));
+            let output = strip_format.unwrap_or(match format {
+                ImageFormat::Jpeg => OutputFormat::Jpeg,
+                ImageFormat::Gif => OutputFormat::Gif,
+                _ => OutputFormat::Png,
+            });
+
+            return Ok((output.content_type(), output.encode(&img, query.quality)?));
+        }
+
+        let colour = img.color();
+        let report = json!({
+            "format": format!("{:?}", format).to_lowercase(),
+            "width": img.width(),
+            "height": img.height(),
+            "color_type": format!("{:?}", colour).to_lowercase(),
+            "bit_depth": colour.bits_per_pixel() / colour.channel_count() as u16,
+            "frames": frame_count(format, bytes),
+            "icc_profile": icc_profile(format, bytes).is_some(),
+            "exif": exif,
+        });
+
+        Ok(("application/json", report.to_string().into_bytes()))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    /// A bitmap with the first `red` columns magical red and the rest grey.
+    /// Bitmaps are uncompressed, so the upload is as large as the pixels.
+    fn bitmap(width: u32, height: u32, red: u32) -> Vec<u8> {
+        let img = RgbImage::from_fn(width, height, |x, _| {
+            if x < red {
+                Rgb([200, 20, 20])
+            } else {
+                Rgb([90, 90, 90])
+            }
+        });
+        let mut encoded = Cursor::new(Vec::new());
+        img.write_to(&mut encoded, ImageOutputFormat::Bmp).unwrap();
+
+        encoded.into_inner()
+    }
+
+    fn form(images: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (i, image) in images.iter().enumerate() {
+            body.extend_from_slice(
+                format!(
+                    "--boundary\r\nContent-Disposition: form-data; name=\"image\"; \
+                    filename=\"{}.bmp\"\r\nContent-Type: image/bmp\r\n\r\n",
+                    i
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(image);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--boundary--\r\n");
+
+        body
+    }
+
+    async fn post(uri: &str, limits: ImageLimits, images: &[Vec<u8>]) -> (StatusCode, Vec<u8>) {
+        let app = test::init_service(
+            App::new()
+                .app_data(limits.multipart_config())
+                .app_data(web::Data::new(AppState {
+                    image_limits: limits,
+                    ..AppState::for_tests()
+                }))
+                .service(day_11_red_pixels)
+                .service(day_11_transform)
+                .service(day_11_metadata),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .insert_header((
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            ))
+            .set_payload(form(images))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        (res.status(), test::read_body(res).await.to_vec())
+    }
+
+    #[actix_web::test]
+    async fn counts_several_images_past_the_default_memory_limit() {
+        // three bitmaps of about 1.4 MiB each, beyond actix-multipart's 2 MiB
+        let images: Vec<_> = [100, 200, 300]
+            .into_iter()
+            .map(|red| bitmap(700, 700, red))
+            .collect();
+        assert!(images.iter().map(Vec::len).sum::<usize>() > 2 << 20);
+
+        let (status, body) = post("/11/red_pixels", ImageLimits::default(), &images).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!([
+                { "count": 70_000, "filename": "0.bmp" },
+                { "count": 140_000, "filename": "1.bmp" },
+                { "count": 210_000, "filename": "2.bmp" },
+            ])
+        );
+    }
+
+    #[actix_web::test]
+    async fn refuses_forms_past_the_upload_limit() {
+        let limits = ImageLimits {
+            max_upload_bytes: 1 << 20,
+            ..ImageLimits::default()
+        };
+        let images = [bitmap(500, 500, 10), bitmap(500, 500, 10)];
+
+        let (status, _) = post("/11/red_pixels", limits, &images).await;
+        assert!(status.is_client_error(), "{}", status);
+
+        let (status, body) = post("/11/red_pixels", ImageLimits::default(), &images[..1]).await;
+        assert_eq!((status, body), (StatusCode::OK, b"5000".to_vec()));
+    }
+
+    #[actix_web::test]
+    async fn transforms_to_the_supported_formats_only() {
+        let image = [bitmap(8, 8, 4)];
+
+        for (format, content_type) in [
+            ("png", "image/png"),
+            ("jpg", "image/jpeg"),
+            ("gif", "image/gif"),
+        ] {
+            let uri = format!("/11/transform?ops=rotate:90&format={}", format);
+            let (status, body) = post(&uri, ImageLimits::default(), &image).await;
+            assert_eq!(status, StatusCode::OK, "{}", format);
+            let decoded = image::load_from_memory(&body).unwrap();
+            assert_eq!(decoded.dimensions(), (8, 8));
+            assert_eq!(
+                image::guess_format(&body).unwrap().to_mime_type(),
+                content_type
+            );
+        }
+
+        let (status, body) =
+            post("/11/transform?format=webp", ImageLimits::default(), &image).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!({ "error": "cannot encode webp images, expected png, jpeg or gif" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn refuses_to_strip_animations() {
+        let frames = (0..3).map(|i| {
+            let img = image::RgbaImage::from_pixel(4, 4, Rgba([i * 80, 0, 0, 255]));
+            image::Frame::new(img)
+        });
+        let mut gif = Vec::new();
+        image::codecs::gif::GifEncoder::new(&mut gif)
+            .encode_frames(frames)
+            .unwrap();
+
+        let (status, body) = post("/11/metadata", ImageLimits::default(), &[gif.clone()]).await;
+        assert_eq!(status, StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["frames"], 3);
+
+        let (status, body) = post("/11/metadata?strip=true", ImageLimits::default(), &[gif]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!({
+                "error": "cannot strip metadata from an animated image of 3 frames without dropping all but the first"
+            })
+        );
 
-    magical_red.to_string()
+        let (status, body) = post(
+            "/11/metadata?strip=true",
+            ImageLimits::default(),
+            &[bitmap(4, 4, 2)],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(image::guess_format(&body).unwrap(), ImageFormat::Png);
+    }
 }
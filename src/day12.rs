@@ -1,30 +1,537 @@
-use actix_web::{get, post, web, HttpResponse, Responder, Result};
-use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
+
+use actix_web::{
+    delete, error, get,
+    http::{header::ContentType, StatusCode},
+    post, put, web, HttpRequest, HttpResponse, Responder, Result,
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Timelike, Utc, Weekday};
+use derive_more::{Display, Error};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use ulid::Ulid;
-use uuid::Uuid;
+use ulid::{Generator, MonotonicError, Ulid};
+use uuid::{Builder, Uuid, Variant};
+
+use crate::{
+    clock::ClockError,
+    ids::{self, IdError, IdForm},
+    storage::{check_key, KeyValueStore, StorageError},
+    tz::{TzError, Zone},
+    AppState,
+};
+
+#[derive(Debug, Display, Error)]
+pub enum PacketError {
+    #[display(fmt = "no packet named {}", _0)]
+    NotFound(#[error(not(source))] String),
+
+    #[display(fmt = "invalid packet name")]
+    InvalidName,
+
+    #[display(fmt = "packet metadata must be a json object: {}", _0)]
+    InvalidMetadata(serde_json::Error),
+
+    #[display(fmt = "give either ttl or expires_at, not both")]
+    ConflictingExpiry,
+
+    #[display(fmt = "metadata of packet {} must be a json object", _0)]
+    InvalidImport(#[error(not(source))] String),
+
+    #[display(fmt = "packet storage failed: {}", _0)]
+    Storage(StorageError),
+
+    #[display(fmt = "count must be between 1 and {}", MAX_GENERATED_IDS)]
+    InvalidCount,
+
+    #[display(fmt = "could not generate ids: {}", _0)]
+    Generate(MonotonicError),
+
+    #[display(fmt = "{}", _0)]
+    TimeZone(TzError),
+
+    #[display(fmt = "{}", _0)]
+    Clock(ClockError),
+
+    #[display(fmt = "weekday must be 0 (monday) to 6 (sunday), not {}", _0)]
+    InvalidWeekday(#[error(not(source))] u8),
+
+    #[display(fmt = "invalid check {}: {}", _0, _1)]
+    InvalidCheck(
+        #[error(not(source))] String,
+        #[error(not(source))] &'static str,
+    ),
+}
+
+impl From<StorageError> for PacketError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::InvalidKey => PacketError::InvalidName,
+            err => PacketError::Storage(err),
+        }
+    }
+}
+
+impl From<ClockError> for PacketError {
+    fn from(err: ClockError) -> Self {
+        PacketError::Clock(err)
+    }
+}
+
+impl error::ResponseError for PacketError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            PacketError::NotFound(_) => StatusCode::NOT_FOUND,
+            PacketError::Clock(ref err) => err.status_code(),
+            PacketError::Storage(_) | PacketError::Generate(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+// how often expired packets are removed from the store
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+const MAX_GENERATED_IDS: usize = 1000;
+
+// the store is shared with other state, so packets live under their own prefix
+const PACKET_PREFIX: &str = "packet:";
+
+fn packet_key(name: &str) -> String {
+    format!("{}{}", PACKET_PREFIX, name)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    saved_at: DateTime<Utc>,
+    metadata: Option<serde_json::Value>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Packet {
+    /// Reads a stored packet whether it is live or not.
+    async fn read(
+        storage: &dyn KeyValueStore,
+        name: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Self>, PacketError> {
+        match storage.get(&packet_key(name)).await? {
+            Some(value) => Packet::decode(&value, now).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn decode(value: &[u8], now: DateTime<Utc>) -> Result<Self, PacketError> {
+        let invalid = match serde_json::from_slice(value) {
+            Ok(packet) => return Ok(packet),
+            Err(err) => err,
+        };
+
+        // older versions stored a bare timestamp, first only the time of day,
+        // which is read as its most recent occurrence
+        let legacy = String::from_utf8_lossy(value);
+        let saved_at = match (legacy.parse::<DateTime<Utc>>(), legacy.parse::<NaiveTime>()) {
+            (Ok(saved_at), _) => saved_at,
+            (_, Ok(time)) => {
+                let saved_at = now.date_naive().and_time(time).and_utc();
+                match saved_at > now {
+                    true => saved_at - Duration::days(1),
+                    false => saved_at,
+                }
+            }
+            (Err(_), Err(_)) => return Err(StorageError::Encoding(invalid).into()),
+        };
+
+        Ok(Packet {
+            saved_at,
+            metadata: None,
+            expires_at: None,
+        })
+    }
+
+    /// Loads a live packet. An expired one is only removed once it has also
+    /// expired by the system clock, so an adjusted clock cannot destroy data.
+    async fn load(
+        storage: &dyn KeyValueStore,
+        name: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Self, PacketError> {
+        let packet = Packet::read(storage, name, now)
+            .await?
+            .ok_or_else(|| PacketError::NotFound(name.to_string()))?;
+
+        if packet.is_expired(now) {
+            if packet.is_expired(Utc::now()) {
+                storage.remove(&packet_key(name)).await?;
+            }
+            return Err(PacketError::NotFound(name.to_string()));
+        }
+
+        Ok(packet)
+    }
+
+    async fn save(&self, storage: &dyn KeyValueStore, name: &str) -> Result<(), PacketError> {
+        Ok(storage.save(&packet_key(name), self).await?)
+    }
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn entry(&self, name: &str) -> PacketEntry {
+        PacketEntry {
+            packet: name.to_string(),
+            saved_at: self.saved_at,
+            expires_at: self.expires_at,
+            metadata: self.metadata.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Every live packet in the store, oldest first. Packets that fail to load
+/// are skipped rather than failing the whole listing.
+async fn all_packets(
+    storage: &dyn KeyValueStore,
+    now: DateTime<Utc>,
+) -> Result<Vec<PacketEntry>, PacketError> {
+    let mut entries = Vec::new();
+    for key in storage.keys_with_prefix(PACKET_PREFIX).await? {
+        let name = &key[PACKET_PREFIX.len()..];
+        if let Ok(packet) = Packet::load(storage, name, now).await {
+            entries.push(packet.entry(name));
+        }
+    }
+    entries.sort_by(|a, b| (a.saved_at, &a.packet).cmp(&(b.saved_at, &b.packet)));
+
+    Ok(entries)
+}
+
+/// Removes expired packets every [`SWEEP_INTERVAL`], for the life of the
+/// process. Expiry is judged by the system clock, never an adjusted one.
+pub async fn sweep_expired(storage: Arc<dyn KeyValueStore>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let Ok(keys) = storage.keys_with_prefix(PACKET_PREFIX).await else {
+            continue;
+        };
+        let now = Utc::now();
+        for key in keys {
+            let name = &key[PACKET_PREFIX.len()..];
+            if let Ok(Some(packet)) = Packet::read(storage.as_ref(), name, now).await {
+                if packet.is_expired(now) {
+                    let _ = storage.remove(&key).await;
+                }
+            }
+        }
+    }
+}
+
+/// Packets used to be stored under their bare names, back when nothing else
+/// shared the store. Moves every unprefixed key that still reads as a packet
+/// under [`PACKET_PREFIX`], returning how many were moved.
+pub async fn prefix_legacy_packets(storage: &dyn KeyValueStore) -> Result<usize, PacketError> {
+    let mut moved = 0;
 
-use crate::AppState;
+    for key in storage.keys_with_prefix("").await? {
+        if key.starts_with(PACKET_PREFIX) {
+            continue;
+        }
+        let Some(value) = storage.get(&key).await? else {
+            continue;
+        };
 
+        if Packet::decode(&value, Utc::now()).is_ok() {
+            storage.put(&packet_key(&key), &value).await?;
+            storage.remove(&key).await?;
+            moved += 1;
+        }
+    }
+
+    Ok(moved)
+}
+
+/// A packet as listed, exported and imported.
+#[derive(Serialize, Deserialize)]
+struct PacketEntry {
+    packet: String,
+    saved_at: DateTime<Utc>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    metadata: serde_json::Value,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ElapsedUnit {
+    #[default]
+    #[serde(alias = "s", alias = "seconds")]
+    Secs,
+    #[serde(alias = "milliseconds")]
+    Ms,
+    #[serde(alias = "iso")]
+    Iso8601,
+}
+
+impl ElapsedUnit {
+    fn format(self, elapsed: Duration) -> String {
+        match self {
+            ElapsedUnit::Secs => elapsed.num_seconds().to_string(),
+            ElapsedUnit::Ms => elapsed.num_milliseconds().to_string(),
+            ElapsedUnit::Iso8601 => iso8601_duration(elapsed),
+        }
+    }
+}
+
+/// Formats a duration as `PnDTnHnMn.nnnS`, leaving out zero components.
+fn iso8601_duration(elapsed: Duration) -> String {
+    let sign = if elapsed < Duration::zero() { "-" } else { "" };
+    let millis = elapsed.num_milliseconds().unsigned_abs();
+
+    let days = millis / 86_400_000;
+    let hours = millis / 3_600_000 % 24;
+    let minutes = millis / 60_000 % 60;
+    let seconds = millis / 1000 % 60;
+    let fraction = millis % 1000;
+
+    let mut time = String::new();
+    if hours > 0 {
+        time += &format!("{}H", hours);
+    }
+    if minutes > 0 {
+        time += &format!("{}M", minutes);
+    }
+    if fraction > 0 {
+        let fraction = format!("{:03}", fraction);
+        time += &format!("{}.{}S", seconds, fraction.trim_end_matches('0'));
+    } else if seconds > 0 || (days == 0 && time.is_empty()) {
+        time += &format!("{}S", seconds);
+    }
+
+    let days = if days > 0 {
+        format!("{}D", days)
+    } else {
+        String::new()
+    };
+    let time = if time.is_empty() {
+        time
+    } else {
+        format!("T{}", time)
+    };
+
+    format!("{}P{}{}", sign, days, time)
+}
+
+#[derive(Deserialize)]
+struct SaveQuery {
+    /// Seconds until the packet expires, it is kept forever without one.
+    ttl: Option<u32>,
+}
+
+/// Saves the current time under the packet name. A json object sent as the
+/// body is kept alongside it as metadata.
 #[post("/12/save/{packet}")]
-pub async fn day_12_save(packet: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+pub async fn day_12_save(
+    packet: web::Path<String>,
+    query: web::Query<SaveQuery>,
+    body: web::Bytes,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
     let packet = packet.into_inner();
 
-    let _ = data.persist.save::<NaiveTime>(&packet, Utc::now().time());
+    let metadata = match body.iter().all(u8::is_ascii_whitespace) {
+        true => None,
+        false => {
+            let metadata: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_slice(&body).map_err(PacketError::InvalidMetadata)?;
+            Some(metadata.into())
+        }
+    };
+
+    let saved_at = data.clock.now_for(&req)?;
+    let packet_data = Packet {
+        saved_at,
+        metadata,
+        expires_at: query
+            .ttl
+            .map(|ttl| saved_at + Duration::seconds(ttl as i64)),
+    };
+    packet_data.save(data.storage.as_ref(), &packet).await?;
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(Deserialize)]
+struct ElapsedQuery {
+    #[serde(default)]
+    unit: ElapsedUnit,
 }
 
 #[get("/12/load/{packet}")]
-pub async fn day_12_load(packet: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
-    let packet = packet.into_inner();
+pub async fn day_12_load(
+    packet: web::Path<String>,
+    query: web::Query<ElapsedQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let now = data.clock.now_for(&req)?;
+    let packet = Packet::load(data.storage.as_ref(), &packet.into_inner(), now).await?;
 
-    let current_time = Utc::now().time();
-    let packet_time = data.persist.load::<NaiveTime>(&packet).unwrap();
+    Ok(query.unit.format(now - packet.saved_at))
+}
+
+#[get("/12/packets/{packet}")]
+pub async fn day_12_packet(
+    packet: web::Path<String>,
+    query: web::Query<ElapsedQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let now = data.clock.now_for(&req)?;
+    let name = packet.into_inner();
+    let packet = Packet::load(data.storage.as_ref(), &name, now).await?;
 
-    let diff = current_time - packet_time.clone();
+    Ok(web::Json(json!({
+        "packet": name,
+        "saved_at": packet.saved_at,
+        "expires_at": packet.expires_at,
+        "elapsed": query.unit.format(now - packet.saved_at),
+        "metadata": packet.metadata,
+    })))
+}
+
+#[get("/12/packets")]
+pub async fn day_12_list_packets(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let now = data.clock.now_for(&req)?;
+
+    Ok(web::Json(all_packets(data.storage.as_ref(), now).await?))
+}
+
+#[delete("/12/packets/{packet}")]
+pub async fn day_12_delete_packet(
+    packet: web::Path<String>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let now = data.clock.now_for(&req)?;
+    let name = packet.into_inner();
+    Packet::load(data.storage.as_ref(), &name, now).await?;
+
+    data.storage.remove(&packet_key(&name)).await?;
+
+    Ok(HttpResponse::Ok())
+}
+
+#[delete("/12/packets")]
+pub async fn day_12_delete_packets(
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let deleted = data.storage.remove_prefix(PACKET_PREFIX).await?;
+
+    Ok(web::Json(json!({ "deleted": deleted })))
+}
+
+#[derive(Deserialize)]
+struct ExpiryReq {
+    /// Seconds from now.
+    ttl: Option<u32>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Sets or, with an empty object, clears a packet's expiry.
+#[put("/12/packets/{packet}/expiry")]
+pub async fn day_12_packet_expiry(
+    packet: web::Path<String>,
+    expiry: web::Json<ExpiryReq>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let now = data.clock.now_for(&req)?;
+    let name = packet.into_inner();
+    let mut packet = Packet::load(data.storage.as_ref(), &name, now).await?;
+
+    packet.expires_at = match (expiry.ttl, expiry.expires_at) {
+        (Some(_), Some(_)) => return Err(PacketError::ConflictingExpiry),
+        (Some(ttl), None) => Some(now + Duration::seconds(ttl as i64)),
+        (None, expires_at) => expires_at,
+    };
+    packet.save(data.storage.as_ref(), &name).await?;
+
+    Ok(web::Json(packet.entry(&name)))
+}
+
+#[get("/12/export")]
+pub async fn day_12_export(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let now = data.clock.now_for(&req)?;
+
+    Ok(web::Json(
+        json!({ "packets": all_packets(data.storage.as_ref(), now).await? }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ImportReq {
+    packets: Vec<PacketEntry>,
+}
 
-    diff.num_seconds().to_string()
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// Remove every stored packet before importing instead of merging.
+    #[serde(default)]
+    replace: bool,
+}
+
+/// Restores an export. Imported packets overwrite stored ones of the same name.
+#[post("/12/import")]
+pub async fn day_12_import(
+    req: web::Json<ImportReq>,
+    query: web::Query<ImportQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let req = req.into_inner();
+
+    // check every entry first so a bad backup leaves the store untouched
+    for entry in &req.packets {
+        if !(entry.metadata.is_null() || entry.metadata.is_object()) {
+            return Err(PacketError::InvalidImport(entry.packet.clone()));
+        }
+        check_key(&packet_key(&entry.packet))?;
+    }
+
+    if query.replace {
+        data.storage.remove_prefix(PACKET_PREFIX).await?;
+    }
+
+    for entry in &req.packets {
+        let packet = Packet {
+            saved_at: entry.saved_at,
+            metadata: (!entry.metadata.is_null()).then(|| entry.metadata.clone()),
+            expires_at: entry.expires_at,
+        };
+        packet.save(data.storage.as_ref(), &entry.packet).await?;
+    }
+
+    Ok(web::Json(json!({ "imported": req.packets.len() })))
 }
 
 #[post("/12/ulids")]
@@ -32,7 +539,7 @@ pub async fn day_12_ulids(ulids: web::Json<Vec<Ulid>>) -> Result<impl Responder>
     let uuids = ulids
         .0
         .iter()
-        .map(|ulid| Uuid::from(ulid.clone()))
+        .map(|ulid| Uuid::from(*ulid))
         .rev()
         .collect::<Vec<Uuid>>();
 
@@ -43,14 +550,16 @@ pub async fn day_12_ulids(ulids: web::Json<Vec<Ulid>>) -> Result<impl Responder>
 pub async fn day_12_lsb(
     weekday: web::Path<u8>,
     ulids: web::Json<Vec<Ulid>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
 ) -> Result<impl Responder> {
+    let current_time = data.clock.now_for(&req)?;
     let weekday = weekday.into_inner();
-    let weekday = Weekday::try_from(weekday).unwrap();
+    let weekday = Weekday::try_from(weekday).map_err(|_| PacketError::InvalidWeekday(weekday))?;
 
     let (christmas_eve_count, weekday_count, future_count, lsb_count) = ulids.0.iter().fold(
         (0, 0, 0, 0),
         |(christmas_eve_cur, weekday_cur, future_cur, lsb_cur), ulid| {
-            let current_time = Utc::now();
             let ulid_time: DateTime<Utc> = ulid.datetime().into();
 
             let month_of_year = ulid_time.month();
@@ -79,3 +588,578 @@ pub async fn day_12_lsb(
         "LSB is 1": lsb_count
     })))
 }
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum IdKind {
+    #[default]
+    Ulid,
+    Uuidv4,
+    Uuidv7,
+}
+
+fn default_count() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+struct GenerateQuery {
+    #[serde(default)]
+    kind: IdKind,
+    #[serde(default = "default_count")]
+    count: usize,
+    /// Defaults to the kind's own form.
+    format: Option<IdForm>,
+}
+
+/// Generates a batch of ids. ULIDs within a batch are monotonic, and so are
+/// v7 UUIDs, which are built from the same generator.
+#[get("/12/ids/generate")]
+pub async fn day_12_generate_ids(
+    query: web::Query<GenerateQuery>,
+) -> Result<impl Responder, PacketError> {
+    if !(1..=MAX_GENERATED_IDS).contains(&query.count) {
+        return Err(PacketError::InvalidCount);
+    }
+
+    let format = query.format.unwrap_or(match query.kind {
+        IdKind::Ulid => IdForm::Ulid,
+        IdKind::Uuidv4 | IdKind::Uuidv7 => IdForm::Uuid,
+    });
+
+    let mut generator = Generator::new();
+    let mut generated = Vec::with_capacity(query.count);
+    for _ in 0..query.count {
+        let value = match query.kind {
+            IdKind::Ulid => generator.generate().map_err(PacketError::Generate)?.0,
+            IdKind::Uuidv4 => Uuid::new_v4().as_u128(),
+            IdKind::Uuidv7 => {
+                // the version and variant bits overwrite the top of the random
+                // part, so increments from the generator still sort
+                let ulid = generator.generate().map_err(PacketError::Generate)?;
+                let random: [u8; 10] = ulid.0.to_be_bytes()[6..].try_into().unwrap();
+                Builder::from_unix_timestamp_millis(ulid.timestamp_ms(), &random)
+                    .into_uuid()
+                    .as_u128()
+            }
+        };
+        generated.push(ids::format(value, format));
+    }
+
+    Ok(web::Json(generated))
+}
+
+#[derive(Deserialize)]
+struct InspectQuery {
+    /// Guessed per id when missing.
+    from: Option<IdForm>,
+}
+
+fn read_id(input: &serde_json::Value, form: Option<IdForm>) -> Result<(u128, IdForm), IdError> {
+    match input {
+        serde_json::Value::String(input) => ids::parse(input, form),
+        serde_json::Value::Number(input) => {
+            ids::parse(&input.to_string(), form.or(Some(IdForm::Integer)))
+        }
+        _ => Err(IdError::NotText),
+    }
+}
+
+/// Describes the value both as a ULID and as a UUID.
+fn inspect_id(value: u128, form: IdForm) -> serde_json::Value {
+    let ulid = Ulid(value);
+    let ulid_time: DateTime<Utc> = ulid.datetime().into();
+    let random = ulid.random();
+
+    let uuid = Uuid::from_u128(value);
+    let uuid_time = uuid.get_timestamp().and_then(|timestamp| {
+        let (seconds, nanos) = timestamp.to_unix();
+        DateTime::<Utc>::from_timestamp(seconds as i64, nanos)
+    });
+    // the version nibble only means something for RFC 4122 uuids
+    let uuid_version =
+        Some(uuid.get_version_num()).filter(|_| uuid.get_variant() == Variant::RFC4122);
+    let uuid_random_bits = match uuid_version {
+        Some(4) => Some(122),
+        Some(7) => Some(74),
+        _ => None,
+    };
+
+    json!({
+        "form": form,
+        "ulid": {
+            "value": ulid.to_string(),
+            "timestamp": ulid_time,
+            "timestamp_ms": ulid.timestamp_ms(),
+            "random": format!("{:020x}", random),
+            "random_ones": random.count_ones(),
+            "lsb": random & 1,
+        },
+        "uuid": {
+            "value": uuid.hyphenated().to_string(),
+            "version": uuid_version,
+            "variant": uuid.get_variant().to_string(),
+            "timestamp": uuid_time,
+            "random_bits": uuid_random_bits,
+        },
+    })
+}
+
+/// Inspects a list of ids in any form. Ids that can't be read get an error
+/// of their own rather than failing the request.
+#[post("/12/ids/inspect")]
+pub async fn day_12_inspect_ids(
+    query: web::Query<InspectQuery>,
+    ids: web::Json<Vec<serde_json::Value>>,
+) -> Result<impl Responder> {
+    let results = ids
+        .iter()
+        .map(|input| {
+            let mut result = match read_id(input, query.from) {
+                Ok((value, form)) => inspect_id(value, form),
+                Err(err) => json!({ "error": err.to_string() }),
+            };
+            result["input"] = input.clone();
+            result
+        })
+        .collect::<Vec<_>>();
+
+    Ok(web::Json(results))
+}
+
+#[derive(Deserialize)]
+struct ConvertQuery {
+    to: IdForm,
+    from: Option<IdForm>,
+}
+
+/// Converts a list of ids to one form, with per-id errors like
+/// `/12/ids/inspect`.
+#[post("/12/ids/convert")]
+pub async fn day_12_convert_ids(
+    query: web::Query<ConvertQuery>,
+    ids: web::Json<Vec<serde_json::Value>>,
+) -> Result<impl Responder> {
+    let results = ids
+        .iter()
+        .map(|input| match read_id(input, query.from) {
+            Ok((value, form)) => json!({
+                "input": input,
+                "from": form,
+                "output": ids::format(value, query.to),
+            }),
+            Err(err) => json!({ "input": input, "error": err.to_string() }),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(web::Json(results))
+}
+
+fn default_time_zone() -> String {
+    "UTC".to_string()
+}
+
+#[derive(Deserialize)]
+struct AnalyzeQuery {
+    /// A tz database name, a fixed offset like `+01:00`, or UTC.
+    #[serde(default = "default_time_zone")]
+    tz: String,
+}
+
+/// A condition on the local date of a timestamp. Every field given must
+/// match, so `{"month": 11, "weekday": ["thu"], "nth": 4}` is Thanksgiving.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DateCheck {
+    name: String,
+    /// Inclusive.
+    from: Option<NaiveDate>,
+    /// Inclusive.
+    to: Option<NaiveDate>,
+    month: Option<u32>,
+    day: Option<u32>,
+    weekday: Option<Vec<Weekday>>,
+    /// Which occurrence of the weekday in the month, negative counting from
+    /// the end.
+    nth: Option<i32>,
+    future: Option<bool>,
+}
+
+impl DateCheck {
+    fn validate(&self) -> Result<(), PacketError> {
+        let invalid = |reason| Err(PacketError::InvalidCheck(self.name.clone(), reason));
+
+        if self.month.is_some_and(|month| !(1..=12).contains(&month)) {
+            return invalid("month must be between 1 and 12");
+        }
+        if self.day.is_some_and(|day| !(1..=31).contains(&day)) {
+            return invalid("day must be between 1 and 31");
+        }
+        if let Some(nth) = self.nth {
+            if nth == 0 || !(-5..=5).contains(&nth) {
+                return invalid("nth must be between 1 and 5 or -5 and -1");
+            }
+            if self.weekday.is_none() {
+                return invalid("nth needs a weekday");
+            }
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from > to {
+                return invalid("from is after to");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn matches(&self, local: NaiveDate, future: bool) -> bool {
+        let days_in_month = (28..=31)
+            .rev()
+            .find(|&day| local.with_day(day).is_some())
+            .unwrap();
+        let nth = match self.nth {
+            Some(nth) if nth < 0 => -(((days_in_month - local.day()) / 7 + 1) as i32),
+            _ => ((local.day() - 1) / 7 + 1) as i32,
+        };
+
+        self.from.is_none_or(|from| local >= from)
+            && self.to.is_none_or(|to| local <= to)
+            && self.month.is_none_or(|month| local.month() == month)
+            && self.day.is_none_or(|day| local.day() == day)
+            && self
+                .weekday
+                .as_ref()
+                .is_none_or(|weekdays| weekdays.contains(&local.weekday()))
+            && self.nth.is_none_or(|wanted| nth == wanted)
+            && self.future.is_none_or(|wanted| future == wanted)
+    }
+}
+
+/// A condition on the 80 random bits of a ULID: either one bit by index, 0
+/// being the least significant, or the bits under a hex mask.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BitCheck {
+    name: String,
+    bit: Option<u32>,
+    #[serde(default = "default_bit_set")]
+    set: bool,
+    mask: Option<String>,
+    /// Defaults to the mask, i.e. every masked bit set.
+    value: Option<String>,
+}
+
+fn default_bit_set() -> bool {
+    true
+}
+
+const RANDOM_BITS: u32 = 80;
+
+impl BitCheck {
+    /// Resolves to the mask and the value the masked bits must equal.
+    fn resolve(&self) -> Result<(u128, u128), PacketError> {
+        let invalid = |reason| PacketError::InvalidCheck(self.name.clone(), reason);
+        let hex = |text: &str| {
+            u128::from_str_radix(text.trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|value| value >> RANDOM_BITS == 0)
+                .ok_or_else(|| invalid("mask and value must be hex within 80 bits"))
+        };
+
+        match (self.bit, &self.mask) {
+            (Some(bit), None) if self.value.is_none() => {
+                if bit >= RANDOM_BITS {
+                    return Err(invalid("bit must be below 80"));
+                }
+                let mask = 1 << bit;
+                Ok((mask, if self.set { mask } else { 0 }))
+            }
+            (None, Some(mask)) => {
+                let mask = hex(mask)?;
+                let value = self.value.as_deref().map(hex).transpose()?.unwrap_or(mask);
+                if value & !mask != 0 {
+                    return Err(invalid("value has bits outside the mask"));
+                }
+                Ok((mask, value))
+            }
+            _ => Err(invalid("give either bit, or mask with an optional value")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AnalyzeRequest {
+    ulids: Vec<serde_json::Value>,
+    #[serde(default)]
+    dates: Vec<DateCheck>,
+    #[serde(default)]
+    bits: Vec<BitCheck>,
+}
+
+/// Histograms of ULID timestamps in a time zone, with counts for each date
+/// and bit check. Months, weekdays (Monday first) and hours are arrays
+/// indexed from zero. Ids that can't be read, or that are too late for the
+/// zone's known rules, are listed under `invalid`.
+#[post("/12/analyze")]
+pub async fn day_12_analyze(
+    query: web::Query<AnalyzeQuery>,
+    body: web::Json<AnalyzeRequest>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PacketError> {
+    let zone = Zone::parse(&query.tz).map_err(PacketError::TimeZone)?;
+
+    let mut names = HashSet::new();
+    for name in body
+        .dates
+        .iter()
+        .map(|c| &c.name)
+        .chain(body.bits.iter().map(|c| &c.name))
+    {
+        if !names.insert(name) {
+            return Err(PacketError::InvalidCheck(
+                name.clone(),
+                "name is used twice",
+            ));
+        }
+    }
+    for check in &body.dates {
+        check.validate()?;
+    }
+    let bit_checks = body
+        .bits
+        .iter()
+        .map(|check| Ok((check.name.clone(), check.resolve()?)))
+        .collect::<Result<Vec<_>, PacketError>>()?;
+
+    let mut years = BTreeMap::<i32, u32>::new();
+    let mut months = [0u32; 12];
+    let mut weekdays = [0u32; 7];
+    let mut hours = [0u32; 24];
+    let mut date_counts = BTreeMap::<&str, u32>::new();
+    let mut bit_counts = BTreeMap::<&str, u32>::new();
+    let mut invalid = Vec::new();
+    let mut count = 0;
+
+    let now = data.clock.now_for(&req)?;
+    for input in &body.ulids {
+        let ulid = match read_id(input, None) {
+            Ok((value, _)) => Ulid(value),
+            Err(err) => {
+                invalid.push(json!({ "input": input, "error": err.to_string() }));
+                continue;
+            }
+        };
+        let utc: DateTime<Utc> = ulid.datetime().into();
+        let local = match zone.to_local(utc) {
+            Ok(local) => local,
+            Err(err) => {
+                invalid.push(json!({ "input": input, "error": err.to_string() }));
+                continue;
+            }
+        };
+        count += 1;
+
+        *years.entry(local.year()).or_default() += 1;
+        months[local.month0() as usize] += 1;
+        weekdays[local.weekday().num_days_from_monday() as usize] += 1;
+        hours[local.hour() as usize] += 1;
+
+        for check in &body.dates {
+            let matched = check.matches(local.date_naive(), utc > now);
+            *date_counts.entry(&check.name).or_default() += matched as u32;
+        }
+        for (name, (mask, value)) in &bit_checks {
+            let matched = ulid.random() & mask == *value;
+            *bit_counts.entry(name).or_default() += matched as u32;
+        }
+    }
+
+    Ok(web::Json(json!({
+        "tz": query.tz,
+        "count": count,
+        "year": years,
+        "month": months,
+        "weekday": weekdays,
+        "hour": hours,
+        "dates": date_counts,
+        "bits": bit_counts,
+        "invalid": invalid,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test::TestRequest, App};
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{clock::OVERRIDE_HEADER, storage::MemoryStore};
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 12, 24, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn decodes_current_and_legacy_packets() {
+        let now = at(10, 0);
+
+        let current = Packet {
+            saved_at: at(9, 15),
+            metadata: Some(json!({ "to": "santa" })),
+            expires_at: Some(at(12, 0)),
+        };
+        let decoded = Packet::decode(&serde_json::to_vec(&current).unwrap(), now).unwrap();
+        assert_eq!(
+            (decoded.saved_at, decoded.metadata, decoded.expires_at),
+            (current.saved_at, current.metadata, current.expires_at)
+        );
+
+        for (legacy, saved_at) in [
+            (
+                "2023-12-20T08:00:00Z",
+                Utc.with_ymd_and_hms(2023, 12, 20, 8, 0, 0).unwrap(),
+            ),
+            // a bare time of day is its latest occurrence, yesterday if still to come
+            ("09:30:00", at(9, 30)),
+            ("10:00:00", at(10, 0)),
+            ("11:45:00", at(11, 45) - Duration::days(1)),
+        ] {
+            let decoded = Packet::decode(legacy.as_bytes(), now).unwrap();
+            assert_eq!(decoded.saved_at, saved_at, "{}", legacy);
+            assert!(decoded.metadata.is_none() && decoded.expires_at.is_none());
+        }
+
+        assert!(matches!(
+            Packet::decode(b"yesterday", now),
+            Err(PacketError::Storage(StorageError::Encoding(_)))
+        ));
+    }
+
+    #[test]
+    fn formats_iso8601_durations() {
+        for (elapsed, formatted) in [
+            (Duration::zero(), "PT0S"),
+            (Duration::milliseconds(1500), "PT1.5S"),
+            (Duration::milliseconds(7), "PT0.007S"),
+            (Duration::minutes(2), "PT2M"),
+            (Duration::days(3), "P3D"),
+            (
+                Duration::hours(3) + Duration::milliseconds(120),
+                "PT3H0.12S",
+            ),
+            (
+                Duration::days(1)
+                    + Duration::hours(1)
+                    + Duration::minutes(1)
+                    + Duration::seconds(1),
+                "P1DT1H1M1S",
+            ),
+            (-Duration::seconds(90), "-PT1M30S"),
+        ] {
+            assert_eq!(iso8601_duration(elapsed), formatted);
+        }
+    }
+
+    #[actix_web::test]
+    async fn moves_legacy_packets_under_the_prefix() {
+        let storage = MemoryStore::default();
+        storage.put("bare", b"09:30:00").await.unwrap();
+        storage
+            .put("stamped", b"2023-12-20T08:00:00Z")
+            .await
+            .unwrap();
+        storage.put("notes", b"not a packet").await.unwrap();
+        storage.put(&packet_key("current"), b"{}").await.unwrap();
+
+        assert_eq!(prefix_legacy_packets(&storage).await.unwrap(), 2);
+        assert_eq!(
+            storage.keys_with_prefix("").await.unwrap(),
+            ["notes", "packet:bare", "packet:current", "packet:stamped"]
+        );
+        assert_eq!(
+            storage.get("packet:bare").await.unwrap().as_deref(),
+            Some(&b"09:30:00"[..])
+        );
+
+        assert_eq!(prefix_legacy_packets(&storage).await.unwrap(), 0);
+    }
+
+    #[actix_web::test]
+    async fn reports_elapsed_time_in_every_unit() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState::for_tests()))
+                .service(day_12_save)
+                .service(day_12_load),
+        )
+        .await;
+        let save = TestRequest::post()
+            .uri("/12/save/gift")
+            .insert_header((OVERRIDE_HEADER, "2023-12-24T10:00:00Z"))
+            .to_request();
+        assert!(actix_web::test::call_service(&app, save)
+            .await
+            .status()
+            .is_success());
+
+        for (unit, elapsed) in [
+            ("", "5430"),
+            ("?unit=s", "5430"),
+            ("?unit=ms", "5430250"),
+            ("?unit=iso", "PT1H30M30.25S"),
+        ] {
+            let load = TestRequest::get()
+                .uri(&format!("/12/load/gift{}", unit))
+                .insert_header((OVERRIDE_HEADER, "2023-12-24T11:30:30.250Z"))
+                .to_request();
+            assert_eq!(
+                actix_web::test::call_and_read_body(&app, load).await,
+                elapsed,
+                "{}",
+                unit
+            );
+        }
+
+        let load = TestRequest::get()
+            .uri("/12/load/gift?unit=fortnights")
+            .to_request();
+        assert_eq!(
+            actix_web::test::call_service(&app, load).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn refuses_weekdays_past_sunday() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState::for_tests()))
+                .service(day_12_lsb),
+        )
+        .await;
+        let ulids = json!(["01BJQ0E1C3Z56ABCD0E11HYX4M"]);
+
+        let req = TestRequest::post()
+            .uri("/12/ulids/6")
+            .set_json(&ulids)
+            .to_request();
+        let res: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["weekday"], 0);
+
+        let req = TestRequest::post()
+            .uri("/12/ulids/7")
+            .set_json(&ulids)
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(
+            actix_web::test::read_body_json::<serde_json::Value, _>(res).await,
+            json!({ "error": "weekday must be 0 (monday) to 6 (sunday), not 7" })
+        );
+    }
+}
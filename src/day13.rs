@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 
-use actix_web::{get, post, web, HttpResponse, Responder, Result};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Result};
 use serde::Deserialize;
 use serde_json::json;
-use sqlx::{Executor, FromRow};
+use sqlx::FromRow;
 
-use crate::AppState;
+use crate::{namespace::NamespaceError, AppState};
+
+// without an X-Namespace header day13 keeps its orders apart from day18's
+pub const DEFAULT_NAMESPACE: &str = "day13";
 
 #[get("/13/sql")]
 async fn day_13_select(data: web::Data<AppState>) -> impl Responder {
@@ -19,14 +22,18 @@ async fn day_13_select(data: web::Data<AppState>) -> impl Responder {
 }
 
 #[post("/13/reset")]
-async fn day_13_reset(data: web::Data<AppState>) -> impl Responder {
-    let _ = &data
-        .pool
-        .execute(include_str!("../schemas/day13schema.sql"))
-        .await
-        .expect("do sql");
-
-    HttpResponse::Ok()
+async fn day_13_reset(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, NamespaceError> {
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
+    sqlx::query("TRUNCATE orders").execute(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok())
 }
 
 #[derive(Deserialize, FromRow)]
@@ -40,10 +47,15 @@ struct Order {
 #[post("/13/orders")]
 async fn day_13_create_orders(
     orders: web::Json<Vec<Order>>,
+    req: HttpRequest,
     data: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<impl Responder, NamespaceError> {
     let orders = orders.into_inner();
 
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
     for order in orders {
         sqlx::query(
             "INSERT INTO orders (id, region_id, gift_name, quantity) VALUES ($1, $2, $3, $4)",
@@ -52,20 +64,27 @@ async fn day_13_create_orders(
         .bind(order.region_id)
         .bind(order.gift_name)
         .bind(order.quantity)
-        .fetch_all(&data.pool)
-        .await
-        .expect("do sql");
+        .execute(&mut *tx)
+        .await?;
     }
+    tx.commit().await?;
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok())
 }
 
 #[get("/13/orders/total")]
-async fn day_13_orders_total(data: web::Data<AppState>) -> Result<impl Responder> {
+async fn day_13_orders_total(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, NamespaceError> {
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
     let orders: Vec<Order> = sqlx::query_as::<_, Order>("SELECT * FROM orders")
-        .fetch_all(&data.pool)
-        .await
-        .expect("do sql");
+        .fetch_all(&mut *tx)
+        .await?;
+    tx.commit().await?;
 
     let gift_sum: i32 = orders.iter().map(|order| order.quantity).sum();
 
@@ -75,11 +94,18 @@ async fn day_13_orders_total(data: web::Data<AppState>) -> Result<impl Responder
 }
 
 #[get("/13/orders/popular")]
-async fn day_13_popular(data: web::Data<AppState>) -> Result<impl Responder> {
+async fn day_13_popular(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, NamespaceError> {
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
     let orders: Vec<Order> = sqlx::query_as::<_, Order>("SELECT * FROM orders")
-        .fetch_all(&data.pool)
-        .await
-        .expect("do sql");
+        .fetch_all(&mut *tx)
+        .await?;
+    tx.commit().await?;
 
     let mut gift_count: HashMap<String, i32> = HashMap::new();
 
@@ -93,12 +119,9 @@ async fn day_13_popular(data: web::Data<AppState>) -> Result<impl Responder> {
         .map(|gift| (gift.0, gift.1))
         .collect::<Vec<_>>();
 
-    popular.sort_by(|a, b| b.1.cmp(&a.1));
+    popular.sort_by(|a, b| b.1.cmp(a.1));
 
-    let popular_name = match popular.get(0) {
-        Some(gift) => Some(gift.0),
-        None => None,
-    };
+    let popular_name = popular.first().map(|gift| gift.0);
 
     Ok(web::Json(json!({
         "popular": popular_name
@@ -24,7 +24,7 @@ pub async fn day_14_unsafe(body: web::Json<HtmlReq>) -> impl Responder {
     let body = body.into_inner();
     let content = body.content;
 
-    Day14UnsafeTemplate { content: content }
+    Day14UnsafeTemplate { content }
 }
 
 #[post("/14/safe")]
@@ -32,5 +32,5 @@ async fn day_14_safe(body: web::Json<HtmlReq>) -> impl Responder {
     let body = body.into_inner();
     let content = body.content;
 
-    Day14SafeTemplate { content: content }
+    Day14SafeTemplate { content }
 }
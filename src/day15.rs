@@ -155,7 +155,6 @@ async fn day_15_game(body: web::Json<PasswordReq>) -> Result<impl Responder, Gam
     let year_sum: i32 = r4rx
         .captures_iter(&input)
         .map(|x| x.extract())
-        .into_iter()
         .map(|(_, [num])| num.parse::<i32>().unwrap_or_default())
         .sum();
 
@@ -207,7 +206,7 @@ async fn day_15_game(body: web::Json<PasswordReq>) -> Result<impl Responder, Gam
     }
 
     // 5
-    if !(new_joy_match_count == 1) {
+    if new_joy_match_count != 1 {
         return Err(GameError::NotJoyfulEnough);
     }
 
@@ -227,7 +226,7 @@ async fn day_15_game(body: web::Json<PasswordReq>) -> Result<impl Responder, Gam
     }
 
     // 9
-    if !input_hex.ends_with("a") {
+    if !input_hex.ends_with('a') {
         return Err(GameError::NotACoffeeBrewer);
     }
 
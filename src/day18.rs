@@ -1,21 +1,30 @@
 use std::collections::HashMap;
 
-use actix_web::{get, post, web, HttpResponse, Responder, Result};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sqlx::{Executor, FromRow};
+use sqlx::FromRow;
 
-use crate::AppState;
+use crate::{namespace::NamespaceError, AppState};
+
+// without an X-Namespace header day18 keeps its orders apart from day13's
+pub const DEFAULT_NAMESPACE: &str = "day18";
 
 #[post("/18/reset")]
-pub async fn day_18_reset(data: web::Data<AppState>) -> impl Responder {
-    let _ = &data
-        .pool
-        .execute(include_str!("../schemas/day18schema.sql"))
-        .await
-        .expect("do sql");
-
-    HttpResponse::Ok()
+pub async fn day_18_reset(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, NamespaceError> {
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
+    sqlx::query("TRUNCATE orders, regions")
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok())
 }
 
 #[derive(Deserialize, FromRow)]
@@ -29,10 +38,15 @@ struct Order {
 #[post("/18/orders")]
 async fn day_18_create_orders(
     orders: web::Json<Vec<Order>>,
+    req: HttpRequest,
     data: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<impl Responder, NamespaceError> {
     let orders = orders.into_inner();
 
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
     for order in orders {
         sqlx::query(
             "INSERT INTO orders (id, region_id, gift_name, quantity) VALUES ($1, $2, $3, $4)",
@@ -41,12 +55,12 @@ async fn day_18_create_orders(
         .bind(order.region_id)
         .bind(order.gift_name)
         .bind(order.quantity)
-        .fetch_all(&data.pool)
-        .await
-        .expect("do sql");
+        .execute(&mut *tx)
+        .await?;
     }
+    tx.commit().await?;
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok())
 }
 
 #[derive(Deserialize)]
@@ -58,20 +72,25 @@ struct Region {
 #[post("/18/regions")]
 async fn day_18_create_regions(
     regions: web::Json<Vec<Region>>,
+    req: HttpRequest,
     data: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<impl Responder, NamespaceError> {
     let regions = regions.into_inner();
 
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
     for region in regions {
         sqlx::query("INSERT INTO regions (id, name) VALUES ($1, $2)")
             .bind(region.id)
             .bind(region.name)
-            .fetch_all(&data.pool)
-            .await
-            .expect("do sql");
+            .execute(&mut *tx)
+            .await?;
     }
+    tx.commit().await?;
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok())
 }
 
 #[derive(FromRow, Serialize)]
@@ -81,7 +100,14 @@ struct RegionTotalRes {
 }
 
 #[get("/18/regions/total")]
-pub async fn day_18_regions_total(data: web::Data<AppState>) -> Result<impl Responder> {
+pub async fn day_18_regions_total(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, NamespaceError> {
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
     // trash query
     let region_totals: Vec<RegionTotalRes> = sqlx::query_as::<_, RegionTotalRes>(
         "SELECT
@@ -101,9 +127,9 @@ FROM
 			WHERE regions.id IN (SELECT region_id FROM orders)
 ",
     )
-    .fetch_all(&data.pool)
-    .await
-    .expect("do sql");
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
 
     Ok(web::Json(json!(region_totals)))
 }
@@ -123,10 +149,16 @@ struct RegionTopGiftsRes {
 #[get("/18/regions/top_list/{max_list}")]
 async fn day_18_top_list(
     max_list: web::Path<usize>,
+    req: HttpRequest,
     data: web::Data<AppState>,
-) -> Result<impl Responder> {
+) -> Result<impl Responder, NamespaceError> {
     let max_list = max_list.into_inner();
 
+    let mut tx = data
+        .namespaces
+        .begin(&data.pool, &req, DEFAULT_NAMESPACE)
+        .await?;
+
     // trash query
     let region_top_gifts: Vec<RegionTopGiftsRow> = sqlx::query_as::<_, RegionTopGiftsRow>(
         "SELECT
@@ -154,9 +186,9 @@ ORDER BY
 	orders.sum DESC,
 	orders.gift_name ASC",
     )
-    .fetch_all(&data.pool)
-    .await
-    .expect("do sql");
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
 
     let mut gift_map: HashMap<String, Vec<Option<String>>> = HashMap::new();
 
@@ -178,11 +210,7 @@ ORDER BY
         .iter()
         .map(|(region_name, top_gifts)| RegionTopGiftsRes {
             region: region_name.clone(),
-            top_gifts: top_gifts
-                .clone()
-                .into_iter()
-                .filter_map(|x| x)
-                .collect::<Vec<_>>(),
+            top_gifts: top_gifts.clone().into_iter().flatten().collect::<Vec<_>>(),
         })
         .collect::<Vec<_>>();
 
@@ -23,23 +23,19 @@ impl Actor for TableTennisWS {
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for TableTennisWS {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(ws::Message::Text(text)) => {
-                if text == "serve" {
-                    self.served = true;
-                } else if text == "ping" && self.served {
-                    ctx.text("pong")
-                }
+        if let Ok(ws::Message::Text(text)) = msg {
+            if text == "serve" {
+                self.served = true;
+            } else if text == "ping" && self.served {
+                ctx.text("pong")
             }
-            _ => (),
         }
     }
 }
 
 #[get("/19/ws/ping")]
 pub async fn day_19_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    let resp = ws::start(TableTennisWS { served: false }, &req, stream);
-    resp
+    ws::start(TableTennisWS { served: false }, &req, stream)
 }
 
 #[derive(Message)]
@@ -228,25 +224,22 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             Ok(msg) => msg,
         };
 
-        match msg {
-            ws::Message::Text(text) => {
-                let m: IncomingBirdAppMessage = serde_json::from_str(&text).unwrap();
-
-                self.addr.do_send(ClientMessage {
-                    id: self.id,
-                    msg: m.message.clone(),
-                    room: self.room,
-                });
-
-                ctx.text(
-                    json!(BroadcastBirdAppMessage {
-                        user: self.name.clone(),
-                        message: m.message.clone()
-                    })
-                    .to_string(),
-                )
-            }
-            _ => (),
+        if let ws::Message::Text(text) = msg {
+            let m: IncomingBirdAppMessage = serde_json::from_str(&text).unwrap();
+
+            self.addr.do_send(ClientMessage {
+                id: self.id,
+                msg: m.message.clone(),
+                room: self.room,
+            });
+
+            ctx.text(
+                json!(BroadcastBirdAppMessage {
+                    user: self.name.clone(),
+                    message: m.message.clone()
+                })
+                .to_string(),
+            )
         }
     }
 }
@@ -276,7 +269,7 @@ async fn day_19_chat(
     ws::start(
         WsChatSession {
             id: 0,
-            room: room,
+            room,
             name: user,
             addr: srv.get_ref().clone(),
         },
@@ -19,7 +19,6 @@ pub async fn day_20_archive_files_size(tar_file: web::Bytes) -> impl Responder {
     let tar_files_size: u64 = tar_file
         .entries()
         .unwrap()
-        .into_iter()
         .map(|file| file.unwrap().header().size().unwrap())
         .sum();
 
@@ -5,7 +5,7 @@ use serde_json::Value;
 use crate::AppState;
 
 fn coordinates_to_dms(lat: f64, lon: f64, precision: u32) -> String {
-    let power_float: f64 = 10u32.pow(precision).try_into().unwrap();
+    let power_float: f64 = 10u32.pow(precision).into();
 
     let lat_d = lat.abs().trunc();
     let lon_d = lon.abs().trunc();
@@ -59,13 +59,13 @@ async fn coords_to_country_name(api_key: &String, coords: String) -> String {
     let positions_with_country = position_data
         .as_array()
         .unwrap()
-        .into_iter()
+        .iter()
         .filter(|entry| match entry.as_object().unwrap().get("country") {
             Some(country_val) => country_val != &Value::Null,
             None => false,
         })
         .collect::<Vec<_>>();
-    let first_position = positions_with_country.get(0).unwrap().as_object().unwrap();
+    let first_position = positions_with_country.first().unwrap().as_object().unwrap();
     let country_name = first_position.get("country").unwrap();
     let country_name = country_name.as_str().unwrap();
 
@@ -1,4 +1,4 @@
-use std::{collections::HashSet, iter};
+use std::collections::HashSet;
 
 use actix_web::{post, web, HttpResponse, Responder};
 
@@ -11,7 +11,7 @@ pub async fn day_22_integers(integers: web::Bytes) -> impl Responder {
             matches.insert(line);
         }
     });
-    let present_count = matches.iter().nth(0).unwrap().parse::<usize>().unwrap();
+    let present_count = matches.iter().next().unwrap().parse::<usize>().unwrap();
     let present_string = "🎁".repeat(present_count);
     HttpResponse::Ok().body(present_string)
 }
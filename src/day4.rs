@@ -1,7 +1,16 @@
-use actix_web::{post, web, HttpResponse, Responder, Result};
+use actix_web::{
+    error,
+    http::{header::ContentType, StatusCode},
+    post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder, Result,
+};
+use derive_more::{Display, Error};
+use futures_util::StreamExt;
 use serde::Deserialize;
 use serde_json::json;
 
+// longest single ndjson/csv record we are willing to buffer while streaming
+const MAX_RECORD_LEN: usize = 64 * 1024;
+
 #[derive(Clone, Default, Deserialize)]
 struct Reindeer {
     name: String,
@@ -20,11 +29,377 @@ struct Reindeer {
     candies_eaten_yesterday: i32,
 }
 
+#[derive(Debug, Display, Error)]
+enum HerdError {
+    #[display(fmt = "unsupported content type, expected json, ndjson or csv")]
+    UnsupportedFormat,
+
+    #[display(fmt = "could not read body: {}", reason)]
+    Payload { reason: String },
+
+    #[display(
+        fmt = "record on line {} is longer than {} bytes",
+        line,
+        MAX_RECORD_LEN
+    )]
+    RecordTooLong { line: usize },
+
+    #[display(fmt = "line {}, column {}: {}", line, column, reason)]
+    InvalidJson {
+        line: usize,
+        column: usize,
+        reason: String,
+    },
+
+    #[display(fmt = "line {}, field {}: {}", line, field, reason)]
+    InvalidField {
+        line: usize,
+        field: usize,
+        reason: String,
+    },
+
+    #[display(fmt = "line {}: {}", line, reason)]
+    InvalidRecord { line: usize, reason: String },
+}
+
+impl error::ResponseError for HerdError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            HerdError::UnsupportedFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            HerdError::RecordTooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl HerdError {
+    fn from_json(err: serde_json::Error, line: usize) -> Self {
+        // serde_json appends its own position, which is always line 1 for a single record
+        let reason = err.to_string();
+        let reason = match reason.rsplit_once(" at line ") {
+            Some((reason, _position)) => reason.to_string(),
+            None => reason,
+        };
+
+        HerdError::InvalidJson {
+            line: line + err.line().saturating_sub(1),
+            column: err.column(),
+            reason,
+        }
+    }
+
+    fn from_csv(err: csv::Error, line: usize) -> Self {
+        let field = match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.field(),
+            csv::ErrorKind::Utf8 { err, .. } => Some(err.field() as u64),
+            _ => None,
+        };
+        let reason = match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.kind().to_string(),
+            _ => err.to_string(),
+        };
+
+        match field {
+            Some(field) => HerdError::InvalidField {
+                line,
+                field: field as usize + 1,
+                reason,
+            },
+            None => HerdError::InvalidRecord { line, reason },
+        }
+    }
+}
+
+enum HerdFormat {
+    Json,
+    NdJson,
+    Csv,
+}
+
+impl HerdFormat {
+    fn from_request(req: &HttpRequest) -> Result<Self, HerdError> {
+        let mime = req.mime_type().map_err(|_| HerdError::UnsupportedFormat)?;
+
+        // a missing content type keeps the original json behaviour
+        match mime.as_ref().map(|mime| mime.essence_str()) {
+            None | Some("application/json") => Ok(HerdFormat::Json),
+            Some("application/x-ndjson") | Some("application/ndjson") => Ok(HerdFormat::NdJson),
+            Some("text/csv") => Ok(HerdFormat::Csv),
+            Some(_) => Err(HerdError::UnsupportedFormat),
+        }
+    }
+}
+
+/// Decodes records as the body arrives, keeping only the record currently
+/// being parsed plus whatever the latest chunk completed.
+enum RecordDecoder {
+    NdJson { pending: Vec<u8>, line: usize },
+    Csv(Box<CsvDecoder>),
+}
+
+impl RecordDecoder {
+    /// Decodes every record completed by `chunk` into `herd`. An empty chunk
+    /// marks the end of the body.
+    fn feed(&mut self, chunk: &[u8], herd: &mut Vec<Reindeer>) -> Result<(), HerdError> {
+        match self {
+            RecordDecoder::NdJson { pending, line } => {
+                let mut rest = chunk;
+                while let Some(pos) = rest.iter().position(|b| *b == b'\n') {
+                    *line += 1;
+                    if pending.len() + pos > MAX_RECORD_LEN {
+                        return Err(HerdError::RecordTooLong { line: *line });
+                    }
+                    pending.extend_from_slice(&rest[..pos]);
+                    herd.extend(Self::decode_json(*line, pending)?);
+                    pending.clear();
+                    rest = &rest[pos + 1..];
+                }
+
+                if pending.len() + rest.len() > MAX_RECORD_LEN {
+                    return Err(HerdError::RecordTooLong { line: *line + 1 });
+                }
+                pending.extend_from_slice(rest);
+                if chunk.is_empty() && !pending.is_empty() {
+                    herd.extend(Self::decode_json(*line + 1, pending)?);
+                    pending.clear();
+                }
+
+                Ok(())
+            }
+            RecordDecoder::Csv(decoder) => decoder.feed(chunk, herd),
+        }
+    }
+
+    fn decode_json(line: usize, record: &[u8]) -> Result<Option<Reindeer>, HerdError> {
+        let record = record.strip_suffix(b"\r").unwrap_or(record);
+        if record.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(None);
+        }
+
+        serde_json::from_slice(record)
+            .map(Some)
+            .map_err(|err| HerdError::from_json(err, line))
+    }
+}
+
+/// One csv reader over the whole body, so quoted fields may span lines.
+struct CsvDecoder {
+    reader: csv_core::Reader,
+    // the first bytes of the body, held until they are known not to be the
+    // start of a byte order mark, which csv_core only strips when it is whole
+    head: Option<Vec<u8>>,
+    headers: Option<csv::StringRecord>,
+    // fields of the record being read, and where each one ends
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+    // bytes of input the record being read has taken so far
+    record_len: usize,
+    // the line it started on, and the line the reader is on now
+    record_line: usize,
+    line: usize,
+}
+
+impl Default for CsvDecoder {
+    fn default() -> Self {
+        CsvDecoder {
+            reader: csv_core::Reader::new(),
+            head: Some(Vec::new()),
+            headers: None,
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+            record_len: 0,
+            record_line: 1,
+            line: 1,
+        }
+    }
+}
+
+impl CsvDecoder {
+    fn feed(&mut self, mut input: &[u8], herd: &mut Vec<Reindeer>) -> Result<(), HerdError> {
+        const BOM: &[u8] = b"\xef\xbb\xbf";
+        let at_end = input.is_empty();
+
+        if let Some(head) = &mut self.head {
+            let take = input.len().min(BOM.len() - head.len());
+            head.extend_from_slice(&input[..take]);
+            input = &input[take..];
+            if !at_end && head.len() < BOM.len() && BOM.starts_with(head) {
+                return Ok(());
+            }
+
+            let head = self.head.take().unwrap_or_default();
+            let head = head.strip_prefix(BOM).unwrap_or(&head);
+            if !head.is_empty() {
+                self.read(head, false, herd)?;
+            }
+        }
+
+        // csv_core takes empty input as the end of the body
+        if input.is_empty() && !at_end {
+            return Ok(());
+        }
+        self.read(input, at_end, herd)
+    }
+
+    fn read(
+        &mut self,
+        mut input: &[u8],
+        at_end: bool,
+        herd: &mut Vec<Reindeer>,
+    ) -> Result<(), HerdError> {
+        loop {
+            if self.record_len == 0 {
+                // blank lines between records are skipped
+                let blank = input
+                    .iter()
+                    .position(|b| !matches!(b, b'\r' | b'\n'))
+                    .unwrap_or(input.len());
+                self.line += input[..blank].iter().filter(|&&b| b == b'\n').count();
+                self.record_line = self.line;
+                input = &input[blank..];
+                if input.is_empty() && !at_end {
+                    return Ok(());
+                }
+            }
+
+            let (result, read, written, ended) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            self.line += input[..read].iter().filter(|&&b| b == b'\n').count();
+            self.record_len += read;
+            self.output_len += written;
+            self.ends_len += ended;
+            input = &input[read..];
+
+            if self.record_len > MAX_RECORD_LEN {
+                return Err(HerdError::RecordTooLong {
+                    line: self.record_line,
+                });
+            }
+
+            match result {
+                csv_core::ReadRecordResult::InputEmpty | csv_core::ReadRecordResult::End => {
+                    return Ok(())
+                }
+                csv_core::ReadRecordResult::OutputFull => {
+                    self.output.resize(self.output.len() * 2, 0)
+                }
+                csv_core::ReadRecordResult::OutputEndsFull => {
+                    self.ends.resize(self.ends.len() * 2, 0)
+                }
+                csv_core::ReadRecordResult::Record => {
+                    herd.extend(self.decode()?);
+                    self.output_len = 0;
+                    self.ends_len = 0;
+                    self.record_len = 0;
+                }
+            }
+        }
+    }
+
+    fn decode(&mut self) -> Result<Option<Reindeer>, HerdError> {
+        let line = self.record_line;
+        let mut record = csv::ByteRecord::new();
+        let mut start = 0;
+        for &end in &self.ends[..self.ends_len] {
+            record.push_field(&self.output[start..end]);
+            start = end;
+        }
+
+        let mut fields =
+            csv::StringRecord::from_byte_record(record).map_err(|err| HerdError::InvalidField {
+                line,
+                field: err.utf8_error().field() + 1,
+                reason: "invalid utf-8".to_string(),
+            })?;
+        fields.trim();
+        if fields.iter().all(str::is_empty) {
+            return Ok(None);
+        }
+
+        match &self.headers {
+            Some(headers) => fields
+                .deserialize(Some(headers))
+                .map(Some)
+                .map_err(|err| HerdError::from_csv(err, line)),
+            None => {
+                self.headers = Some(fields);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Folds every reindeer in the request body into `acc`.
+///
+/// Json bodies are still read whole, but ndjson and csv bodies are consumed
+/// chunk by chunk so only the record currently being parsed is held in memory.
+async fn fold_herd<T>(
+    req: &HttpRequest,
+    payload: web::Payload,
+    mut acc: T,
+    mut f: impl FnMut(T, Reindeer) -> T,
+) -> Result<T, HerdError> {
+    let mut decoder = match HerdFormat::from_request(req)? {
+        HerdFormat::Json => {
+            let body = web::Bytes::from_request(req, &mut payload.into_inner())
+                .await
+                .map_err(|err| HerdError::Payload {
+                    reason: err.to_string(),
+                })?;
+            let reindeer: Vec<Reindeer> =
+                serde_json::from_slice(&body).map_err(|err| HerdError::from_json(err, 1))?;
+
+            return Ok(reindeer.into_iter().fold(acc, f));
+        }
+        HerdFormat::NdJson => RecordDecoder::NdJson {
+            pending: Vec::new(),
+            line: 0,
+        },
+        HerdFormat::Csv => RecordDecoder::Csv(Box::default()),
+    };
+
+    let mut payload = payload;
+    let mut herd = Vec::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| HerdError::Payload {
+            reason: err.to_string(),
+        })?;
+        if chunk.is_empty() {
+            continue;
+        }
+
+        decoder.feed(&chunk, &mut herd)?;
+        acc = herd.drain(..).fold(acc, &mut f);
+    }
+
+    decoder.feed(&[], &mut herd)?;
+    Ok(herd.drain(..).fold(acc, f))
+}
+
 #[post("/4/strength")]
-pub async fn day_4_strength(reindeer: web::Json<Vec<Reindeer>>) -> impl Responder {
-    let group_strength: i32 = reindeer.0.iter().map(|deer| deer.strength).sum();
+pub async fn day_4_strength(
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<impl Responder, HerdError> {
+    let group_strength: i32 = fold_herd(&req, payload, 0, |sum, deer| sum + deer.strength).await?;
 
-    HttpResponse::Ok().body(group_strength.to_string())
+    Ok(HttpResponse::Ok().body(group_strength.to_string()))
 }
 
 #[derive(Default)]
@@ -36,11 +411,15 @@ struct ContestResults {
 }
 
 #[post("/4/contest")]
-pub async fn day_4_contest(reindeer: web::Json<Vec<Reindeer>>) -> Result<impl Responder> {
-    let results = reindeer
-        .0
-        .iter()
-        .fold(ContestResults::default(), |mut results, deer| {
+pub async fn day_4_contest(
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<impl Responder, HerdError> {
+    let results = fold_herd(
+        &req,
+        payload,
+        ContestResults::default(),
+        |mut results, deer| {
             if results.fastest.speed < deer.speed {
                 results.fastest = deer.clone()
             }
@@ -55,7 +434,9 @@ pub async fn day_4_contest(reindeer: web::Json<Vec<Reindeer>>) -> Result<impl Re
             }
 
             results
-        });
+        },
+    )
+    .await?;
 
     Ok(web::Json(json!({
       "fastest": format!("Speeding past the finish line with a strength of {} is {}", results.fastest.strength, results.fastest.name),
@@ -64,3 +445,118 @@ pub async fn day_4_contest(reindeer: web::Json<Vec<Reindeer>>) -> Result<impl Re
       "consumer": format!("{} ate lots of candies, but also some {}", results.consumer.name, results.consumer.favorite_food)
     })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `body` to a fresh decoder `chunk` bytes at a time and returns the
+    /// name and strength of every reindeer, or the error as the client sees it.
+    fn decode(
+        decoder: fn() -> RecordDecoder,
+        body: &[u8],
+        chunk: usize,
+    ) -> Result<Vec<(String, i32)>, String> {
+        let mut decoder = decoder();
+        let mut herd = Vec::new();
+        for chunk in body.chunks(chunk) {
+            decoder
+                .feed(chunk, &mut herd)
+                .map_err(|err| err.to_string())?;
+        }
+        decoder
+            .feed(&[], &mut herd)
+            .map_err(|err| err.to_string())?;
+
+        Ok(herd
+            .into_iter()
+            .map(|deer| (deer.name, deer.strength))
+            .collect())
+    }
+
+    fn csv() -> RecordDecoder {
+        RecordDecoder::Csv(Box::default())
+    }
+
+    fn ndjson() -> RecordDecoder {
+        RecordDecoder::NdJson {
+            pending: Vec::new(),
+            line: 0,
+        }
+    }
+
+    /// Decodes `body` at every chunk size and checks they all agree.
+    fn decode_at_every_chunk_size(
+        decoder: fn() -> RecordDecoder,
+        body: &str,
+    ) -> Result<Vec<(String, i32)>, String> {
+        let whole = decode(decoder, body.as_bytes(), body.len());
+        for chunk in 1..body.len() {
+            assert_eq!(
+                decode(decoder, body.as_bytes(), chunk),
+                whole,
+                "chunks of {} bytes",
+                chunk
+            );
+        }
+
+        whole
+    }
+
+    fn herd(deer: &[(&str, i32)]) -> Result<Vec<(String, i32)>, String> {
+        Ok(deer
+            .iter()
+            .map(|&(name, strength)| (name.to_string(), strength))
+            .collect())
+    }
+
+    #[test]
+    fn splits_csv_anywhere() {
+        let body =
+            "\u{feff}name,strength,speed\r\n\r\nDasher,5,1.5\r\n\"Dan\r\ncer\",7,2\n\n \nVixen,3,0";
+        assert_eq!(
+            decode_at_every_chunk_size(csv, body),
+            herd(&[("Dasher", 5), ("Dan\r\ncer", 7), ("Vixen", 3)])
+        );
+    }
+
+    #[test]
+    fn reports_csv_errors_on_the_line_they_start() {
+        let body = "name,strength\n\n\"Dan\ncer\",7\nPrancer,lots\n";
+        assert_eq!(
+            decode_at_every_chunk_size(csv, body),
+            Err("line 5, field 2: invalid digit found in string".to_string())
+        );
+
+        let body = "name,strength\r\n\"Comet\",4\r\n\"Cupid\r\n\r\n\",x\r\n";
+        assert_eq!(
+            decode_at_every_chunk_size(csv, body),
+            Err("line 3, field 2: invalid digit found in string".to_string())
+        );
+    }
+
+    #[test]
+    fn splits_ndjson_anywhere() {
+        let body = "{\"name\":\"Dasher\",\"strength\":5}\r\n\r\n  \n{\"name\":\"Dan\\ncer\",\"strength\":7}";
+        assert_eq!(
+            decode_at_every_chunk_size(ndjson, body),
+            herd(&[("Dasher", 5), ("Dan\ncer", 7)])
+        );
+    }
+
+    #[test]
+    fn reports_ndjson_errors_on_their_line() {
+        let body = "{\"name\":\"Dasher\",\"strength\":5}\n\n{\"name\":\"Prancer\",\"strength\":\"lots\"}\r\n";
+        assert_eq!(
+            decode_at_every_chunk_size(ndjson, body),
+            Err("line 3, column 35: invalid type: string \"lots\", expected i32".to_string())
+        );
+
+        // a record cannot continue onto the next line
+        let body = "{\"name\":\"Dasher\",\"strength\":5}\r\n\r\n  \n{\"name\":\"Dan\\ncer\",\n\"strength\":7}\n";
+        assert_eq!(
+            decode_at_every_chunk_size(ndjson, body),
+            Err("line 4, column 19: EOF while parsing a value".to_string())
+        );
+    }
+}
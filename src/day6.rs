@@ -1,45 +1,322 @@
-use actix_web::{post, web, Responder, Result};
+use std::sync::OnceLock;
+
+use actix_web::{
+    error,
+    http::{header::ContentType, StatusCode},
+    post, web, HttpResponse, Responder, Result,
+};
+use aho_corasick::{
+    automaton::{Automaton, StateID},
+    dfa::DFA,
+    AhoCorasick, Anchored, PatternID,
+};
+use derive_more::{Display, Error};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
-#[post("/6")]
-pub async fn day_6(body: web::Bytes) -> Result<impl Responder> {
-    let doc: Vec<_> = body
-        .windows(3)
-        .enumerate()
-        .filter_map(|(_pos, chunk)| {
-            if String::from_utf8(chunk.to_vec()).unwrap_or_default() == "elf" {
-                return Some("elf");
+const ELF: PatternID = PatternID::ZERO;
+const SHELF: PatternID = PatternID::new_unchecked(1);
+const ELF_ON_A_SHELF: PatternID = PatternID::new_unchecked(2);
+
+fn elf_automaton() -> &'static DFA {
+    static AUTOMATON: OnceLock<DFA> = OnceLock::new();
+
+    AUTOMATON.get_or_init(|| DFA::new(["elf", "shelf", "elf on a shelf"]).expect("build automaton"))
+}
+
+/// Counts elves and shelves one byte at a time. The automaton state is kept
+/// between chunks, so matches split across chunk boundaries are still found.
+struct ElfScanner {
+    state: StateID,
+    elf: usize,
+    shelf: usize,
+    elf_on_shelf: usize,
+}
+
+impl ElfScanner {
+    fn new() -> Self {
+        ElfScanner {
+            state: elf_automaton()
+                .start_state(Anchored::No)
+                .expect("unanchored start state"),
+            elf: 0,
+            shelf: 0,
+            elf_on_shelf: 0,
+        }
+    }
+
+    fn scan(&mut self, chunk: &[u8]) {
+        let automaton = elf_automaton();
+
+        for byte in chunk {
+            self.state = automaton.next_state(Anchored::No, self.state, *byte);
+            if !automaton.is_match(self.state) {
+                continue;
             }
-            None
-        })
-        .collect();
 
-    let elf_count = doc.len();
-
-    let (elf_on_shelf_count, shelf_no_elf_count) = body
-        .windows(5)
-        .enumerate()
-        .filter_map(|(pos, chunk)| {
-            if String::from_utf8(chunk.to_vec()).unwrap_or_default() == "shelf" {
-                if (String::from_utf8(body.slice(..pos).to_vec()))
-                    .unwrap_or_default()
-                    .ends_with("elf on a ")
-                {
-                    return Some(&true);
-                } else {
-                    return Some(&false);
+            // "shelf" and "elf on a shelf" both end with "elf", so a single
+            // state can report several patterns at once
+            for i in 0..automaton.match_len(self.state) {
+                match automaton.match_pattern(self.state, i) {
+                    ELF => self.elf += 1,
+                    SHELF => self.shelf += 1,
+                    ELF_ON_A_SHELF => self.elf_on_shelf += 1,
+                    _ => unreachable!(),
                 }
             }
-            None
-        })
-        .fold((0, 0), |(yay, nay), cur| {
-            // woah. cool
-            (yay + (cur == &true) as i32, nay + (cur == &false) as i32)
-        });
+        }
+    }
+}
+
+#[post("/6")]
+pub async fn day_6(mut body: web::Payload) -> Result<impl Responder> {
+    let mut scanner = ElfScanner::new();
+
+    while let Some(chunk) = body.next().await {
+        scanner.scan(&chunk?);
+    }
 
     Ok(web::Json(json!({
-        "elf": elf_count,
-        "elf on a shelf": elf_on_shelf_count,
-        "shelf with no elf on it": shelf_no_elf_count
+        "elf": scanner.elf,
+        "elf on a shelf": scanner.elf_on_shelf,
+        "shelf with no elf on it": scanner.shelf - scanner.elf_on_shelf
     })))
 }
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Normalization {
+    #[default]
+    None,
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct CountReq {
+    text: String,
+    patterns: Vec<String>,
+    #[serde(default = "default_true")]
+    case_sensitive: bool,
+    #[serde(default)]
+    normalization: Normalization,
+    #[serde(default)]
+    whole_word: bool,
+    #[serde(default = "default_true")]
+    overlapping: bool,
+    #[serde(default)]
+    offsets: bool,
+}
+
+impl CountReq {
+    fn fold(&self, s: &str) -> String {
+        let normalized: String = match self.normalization {
+            Normalization::None => s.to_string(),
+            Normalization::Nfc => s.nfc().collect(),
+            Normalization::Nfd => s.nfd().collect(),
+            Normalization::Nfkc => s.nfkc().collect(),
+            Normalization::Nfkd => s.nfkd().collect(),
+        };
+
+        if self.case_sensitive {
+            normalized
+        } else {
+            normalized.to_lowercase()
+        }
+    }
+}
+
+#[derive(Debug, Display, Error)]
+enum CountError {
+    #[display(fmt = "at least one pattern is required")]
+    NoPatterns,
+
+    #[display(fmt = "patterns must not be empty")]
+    EmptyPattern,
+
+    #[display(fmt = "could not build matcher: {}", _0)]
+    Matcher(aho_corasick::BuildError),
+}
+
+impl error::ResponseError for CountError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+/// Text after case folding and normalisation, remembering which original
+/// bytes every folded byte came from so offsets can be reported against the
+/// request text.
+struct FoldedText {
+    text: String,
+    origin: Vec<(usize, usize)>,
+}
+
+impl FoldedText {
+    fn new(req: &CountReq) -> Self {
+        let mut text = String::with_capacity(req.text.len());
+        let mut origin = Vec::with_capacity(req.text.len());
+
+        // normalisation and lowercasing never reach across a grapheme cluster
+        for (start, cluster) in req.text.grapheme_indices(true) {
+            let folded = req.fold(cluster);
+            origin.extend(std::iter::repeat_n(
+                (start, start + cluster.len()),
+                folded.len(),
+            ));
+            text.push_str(&folded);
+        }
+
+        FoldedText { text, origin }
+    }
+
+    fn original_range(&self, start: usize, end: usize) -> (usize, usize) {
+        (self.origin[start].0, self.origin[end - 1].1)
+    }
+
+    fn is_whole_word(&self, start: usize, end: usize) -> bool {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+
+        !self.text[..start].chars().next_back().is_some_and(is_word)
+            && !self.text[end..].chars().next().is_some_and(is_word)
+    }
+}
+
+#[derive(Serialize)]
+struct PhraseMatch {
+    start: usize,
+    end: usize,
+    char_start: usize,
+    char_end: usize,
+}
+
+#[post("/6/count")]
+pub async fn day_6_count(body: web::Json<CountReq>) -> Result<impl Responder, CountError> {
+    let req = body.into_inner();
+
+    if req.patterns.is_empty() {
+        return Err(CountError::NoPatterns);
+    }
+    if req.patterns.iter().any(|pattern| pattern.is_empty()) {
+        return Err(CountError::EmptyPattern);
+    }
+
+    // several requested patterns can fold down to the same needle
+    let mut needles: Vec<String> = Vec::new();
+    let needle_ids: Vec<usize> = req
+        .patterns
+        .iter()
+        .map(|pattern| {
+            let needle = req.fold(pattern);
+            match needles.iter().position(|n| n == &needle) {
+                Some(id) => id,
+                None => {
+                    needles.push(needle);
+                    needles.len() - 1
+                }
+            }
+        })
+        .collect();
+
+    let matcher = AhoCorasick::new(&needles).map_err(CountError::Matcher)?;
+    let folded = FoldedText::new(&req);
+
+    let mut found: Vec<Vec<(usize, usize)>> = vec![Vec::new(); needles.len()];
+    for m in matcher.find_overlapping_iter(&folded.text) {
+        let (start, end) = (m.start(), m.end());
+        if req.whole_word && !folded.is_whole_word(start, end) {
+            continue;
+        }
+
+        // matches of one needle arrive in order, so dropping the ones that
+        // start inside the previous match keeps the leftmost non-overlapping set
+        let needle_matches = &mut found[m.pattern().as_usize()];
+        if !req.overlapping
+            && needle_matches
+                .last()
+                .is_some_and(|(_, last_end)| start < *last_end)
+        {
+            continue;
+        }
+
+        needle_matches.push((start, end));
+    }
+
+    let char_starts: Vec<usize> = req.text.char_indices().map(|(pos, _)| pos).collect();
+    let char_index = |byte: usize| char_starts.partition_point(|start| *start < byte);
+
+    let mut counts = serde_json::Map::new();
+    let mut matches = serde_json::Map::new();
+    for (pattern, needle_id) in req.patterns.iter().zip(needle_ids) {
+        counts.insert(pattern.clone(), json!(found[needle_id].len()));
+
+        if req.offsets {
+            let pattern_matches: Vec<PhraseMatch> = found[needle_id]
+                .iter()
+                .map(|(start, end)| {
+                    let (start, end) = folded.original_range(*start, *end);
+                    PhraseMatch {
+                        start,
+                        end,
+                        char_start: char_index(start),
+                        char_end: char_index(end),
+                    }
+                })
+                .collect();
+            matches.insert(pattern.clone(), json!(pattern_matches));
+        }
+    }
+
+    if req.offsets {
+        Ok(web::Json(json!({
+            "counts": counts,
+            "matches": matches
+        })))
+    } else {
+        Ok(web::Json(json!({
+            "counts": counts
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(text: &str, chunk: usize) -> (usize, usize, usize) {
+        let mut scanner = ElfScanner::new();
+        for chunk in text.as_bytes().chunks(chunk) {
+            scanner.scan(chunk);
+        }
+
+        (scanner.elf, scanner.shelf, scanner.elf_on_shelf)
+    }
+
+    #[test]
+    fn finds_matches_split_across_chunks() {
+        let text = "there is an elf on a shelf on an elf.\nthere is also another shelf in Belfast. elf on a shelf on a shelf";
+        let whole = counts(text, text.len());
+        assert_eq!(whole, (8, 4, 3));
+
+        for chunk in [1, 2, 3] {
+            assert_eq!(counts(text, chunk), whole, "chunks of {} bytes", chunk);
+        }
+    }
+}
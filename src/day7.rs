@@ -1,85 +1,1650 @@
 use std::collections::HashMap;
 
-use actix_web::{get, web, HttpRequest, HttpResponse, Responder, Result};
-use base64::Engine;
-use reqwest::header::HeaderValue;
-use serde::Deserialize;
+use actix_web::{
+    cookie::Cookie,
+    delete, error, get,
+    http::{header::ContentType, StatusCode},
+    post, put, web, HttpRequest, HttpResponse, Responder, Result,
+};
+use base64::{
+    engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use derive_more::{Display, Error};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::json;
+use sha2::Sha256;
+use sqlx::{FromRow, PgConnection};
 
-fn decode_cookie_header(cookie_header: &HeaderValue) -> String {
-    let header_value = cookie_header.to_str().unwrap();
-    let recipe_encoded = &header_value["recipe=".len()..];
+use crate::AppState;
 
-    String::from_utf8(
-        base64::engine::general_purpose::STANDARD
-            .decode(recipe_encoded)
-            .unwrap(),
-    )
-    .unwrap()
+const RECIPE_COOKIE: &str = "recipe";
+
+// recipe, ingredient and owner names are VARCHAR(100) columns
+const MAX_NAME_LEN: usize = 100;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Display, Error)]
+enum RecipeError {
+    #[display(fmt = "missing recipe cookie")]
+    MissingCookie,
+
+    #[display(fmt = "recipe cookie is not valid base64")]
+    InvalidBase64,
+
+    #[display(fmt = "recipe cookie is not valid utf-8")]
+    InvalidUtf8,
+
+    #[display(fmt = "recipe cookie signature does not match")]
+    BadSignature,
+
+    #[display(fmt = "recipe cookie must be signed")]
+    Unsigned,
+
+    #[display(fmt = "invalid recipe: {}", _0)]
+    InvalidRecipe(serde_json::Error),
+
+    #[display(fmt = "recipe and pantry use incompatible units for {}", ingredient)]
+    IncompatibleUnits { ingredient: String },
+
+    #[display(fmt = "recipe {} does not use any ingredients", recipe)]
+    EmptyRecipe { recipe: String },
+
+    #[display(fmt = "recipe and pantry must be given together")]
+    IncompleteStoredBake,
+
+    #[display(fmt = "deducting from a pantry needs POST /7/pantries/{{id}}/bake")]
+    DeductNeedsPost,
+
+    #[display(fmt = "{} names must be 1 to {} characters", kind, MAX_NAME_LEN)]
+    InvalidName { kind: &'static str },
+
+    #[display(fmt = "recipe {} not found", name)]
+    RecipeNotFound { name: String },
+
+    #[display(fmt = "recipe {} already exists", name)]
+    RecipeExists { name: String },
+
+    #[display(fmt = "pantry {} not found", id)]
+    PantryNotFound { id: i32 },
+
+    #[display(fmt = "interrupted before finishing")]
+    Interrupted,
+
+    #[display(fmt = "database error")]
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for RecipeError {
+    fn from(err: sqlx::Error) -> Self {
+        RecipeError::Database(err)
+    }
+}
+
+impl error::ResponseError for RecipeError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            RecipeError::BadSignature | RecipeError::Unsigned => StatusCode::UNAUTHORIZED,
+            RecipeError::RecipeNotFound { .. } | RecipeError::PantryNotFound { .. } => {
+                StatusCode::NOT_FOUND
+            }
+            RecipeError::RecipeExists { .. } => StatusCode::CONFLICT,
+            RecipeError::DeductNeedsPost => StatusCode::METHOD_NOT_ALLOWED,
+            RecipeError::Interrupted | RecipeError::Database(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Decodes standard or url-safe base64, with or without padding.
+fn decode_base64(value: &str) -> Result<Vec<u8>, RecipeError> {
+    let value: String = value
+        .trim()
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+
+    STANDARD_NO_PAD
+        .decode(value)
+        .map_err(|_| RecipeError::InvalidBase64)
+}
+
+fn recipe_mac(key: &[u8], payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn sign_recipe(key: &[u8], recipe: &str) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(recipe);
+    let signature = URL_SAFE_NO_PAD.encode(recipe_mac(key, &payload).finalize().into_bytes());
+
+    format!("{payload}.{signature}")
+}
+
+/// Reads the recipe cookie. Signed cookies (`payload.signature`) must verify,
+/// plain base64 cookies are accepted as they always were unless
+/// `signed_only` is set.
+fn decode_recipe_cookie(
+    req: &HttpRequest,
+    key: &[u8],
+    signed_only: bool,
+) -> Result<String, RecipeError> {
+    let cookie = req
+        .cookie(RECIPE_COOKIE)
+        .ok_or(RecipeError::MissingCookie)?;
+
+    let payload = match cookie.value().split_once('.') {
+        Some((payload, signature)) => {
+            let signature = decode_base64(signature)?;
+            recipe_mac(key, payload)
+                .verify_slice(&signature)
+                .map_err(|_| RecipeError::BadSignature)?;
+            payload
+        }
+        None if signed_only => return Err(RecipeError::Unsigned),
+        None => cookie.value(),
+    };
+
+    String::from_utf8(decode_base64(payload)?).map_err(|_| RecipeError::InvalidUtf8)
 }
 
 #[get("/7/decode")]
-pub async fn day_7_decode(req: HttpRequest) -> impl Responder {
-    let cookie_header = req.headers().get("Cookie").unwrap();
-    let recipe = decode_cookie_header(&cookie_header);
+pub async fn day_7_decode(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let recipe = decode_recipe_cookie(
+        &req,
+        &data.secrets.recipe_cookie_key,
+        data.secrets.signed_recipes_only,
+    )?;
+
+    Ok(HttpResponse::Ok().body(recipe))
+}
+
+#[post("/7/sign")]
+pub async fn day_7_sign(
+    recipe: web::Json<serde_json::Value>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let value = sign_recipe(&data.secrets.recipe_cookie_key, &recipe.0.to_string());
+    let cookie = Cookie::build(RECIPE_COOKIE, value.clone())
+        .path("/7")
+        .http_only(true)
+        .finish();
+
+    HttpResponse::Ok().cookie(cookie).body(value)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dimension {
+    Mass,
+    Volume,
+    Count,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum Unit {
+    Gram,
+    Kilogram,
+    Millilitre,
+    Cup,
+    Piece,
+}
+
+impl Unit {
+    fn dimension(&self) -> Dimension {
+        match self {
+            Unit::Gram | Unit::Kilogram => Dimension::Mass,
+            Unit::Millilitre | Unit::Cup => Dimension::Volume,
+            Unit::Piece => Dimension::Count,
+        }
+    }
+
+    /// Size of one of this unit in thousandths of a gram, millilitre or piece.
+    fn base_factor(&self) -> u128 {
+        match self {
+            Unit::Gram | Unit::Millilitre | Unit::Piece => 1000,
+            Unit::Kilogram => 1_000_000,
+            // US customary cup, 236.588 ml
+            Unit::Cup => 236_588,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Gram => "g",
+            Unit::Kilogram => "kg",
+            Unit::Millilitre => "ml",
+            Unit::Cup => "cups",
+            Unit::Piece => "pieces",
+        }
+    }
+}
+
+impl TryFrom<String> for Unit {
+    type Error = String;
+
+    fn try_from(unit: String) -> Result<Self, Self::Error> {
+        match unit.trim().to_lowercase().as_str() {
+            "g" | "gram" | "grams" => Ok(Unit::Gram),
+            "kg" | "kilogram" | "kilograms" => Ok(Unit::Kilogram),
+            "ml" | "millilitre" | "millilitres" | "milliliter" | "milliliters" => {
+                Ok(Unit::Millilitre)
+            }
+            "cup" | "cups" => Ok(Unit::Cup),
+            "piece" | "pieces" | "pc" | "pcs" => Ok(Unit::Piece),
+            other => Err(format!("unknown unit `{other}`")),
+        }
+    }
+}
+
+// amounts are counted in millionths of their unit
+const MICROS: u128 = 1_000_000;
+
+// the largest amount accepted, in whole units
+const MAX_AMOUNT: u128 = u64::MAX as u128;
+
+/// Reads a non-negative decimal as millionths, rounding past six places.
+fn parse_micros(text: &str) -> Option<u128> {
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if (whole.is_empty() && fraction.is_empty())
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let whole: u128 = match whole {
+        "" => 0,
+        whole => whole.parse().ok()?,
+    };
+    let mut micros = whole.checked_mul(MICROS)?;
+    let mut scale = MICROS;
+    for digit in fraction.bytes().take(6) {
+        scale /= 10;
+        micros += (digit - b'0') as u128 * scale;
+    }
+    if fraction
+        .as_bytes()
+        .get(6)
+        .is_some_and(|&digit| digit >= b'5')
+    {
+        micros += 1;
+    }
+
+    Some(micros)
+}
+
+/// Parses whole numbers, decimals, fractions and mixed numbers such as
+/// `2 1/2`, as millionths.
+fn parse_amount(amount: &str) -> Result<u128, String> {
+    let invalid = || format!("invalid amount `{amount}`");
+    let parse_part = |part: &str| -> Result<u128, String> {
+        match part.split_once('/') {
+            Some((num, den)) => {
+                let num = parse_micros(num.trim()).ok_or_else(invalid)?;
+                let den = parse_micros(den.trim())
+                    .filter(|&den| den > 0)
+                    .ok_or_else(invalid)?;
+                // to the nearest millionth
+                let num = num.checked_mul(MICROS).ok_or_else(invalid)?;
+                Ok(num / den + (num % den >= den - den / 2) as u128)
+            }
+            None => parse_micros(part.trim()).ok_or_else(invalid),
+        }
+    };
+
+    let mut parts = amount.split_whitespace().peekable();
+    if parts.peek().is_none() {
+        return Err(invalid());
+    }
+
+    parts.try_fold(0_u128, |sum, part| {
+        sum.checked_add(parse_part(part)?).ok_or_else(invalid)
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawQuantity {
+    Whole(u64),
+    Number(f64),
+    Text(String),
+    Object {
+        amount: RawAmount,
+        unit: Option<Unit>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAmount {
+    Whole(u64),
+    Number(f64),
+    Text(String),
+}
+
+impl RawAmount {
+    fn micros(self) -> Result<u128, String> {
+        match self {
+            RawAmount::Whole(amount) => Ok(amount as u128 * MICROS),
+            RawAmount::Number(amount) if !amount.is_finite() || amount < 0.0 => Err(format!(
+                "amounts must be non-negative numbers, got {amount}"
+            )),
+            RawAmount::Number(amount) if amount > MAX_AMOUNT as f64 => {
+                Err(format!("amounts must be at most {MAX_AMOUNT}"))
+            }
+            RawAmount::Number(amount) => Ok((amount * MICROS as f64).round() as u128),
+            RawAmount::Text(amount) => parse_amount(&amount),
+        }
+    }
+}
+
+/// An ingredient amount, counted in millionths of its unit so whole numbers
+/// and decimals of up to six places are exact; fractions such as `1/3` are
+/// rounded to the nearest millionth.
+///
+/// Bare numbers carry no unit and are read in the unit of whatever they are
+/// compared against, which keeps plain integer recipes working as before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawQuantity")]
+struct Quantity {
+    micros: u128,
+    unit: Option<Unit>,
+}
+
+impl TryFrom<RawQuantity> for Quantity {
+    type Error = String;
+
+    fn try_from(raw: RawQuantity) -> Result<Self, Self::Error> {
+        let (micros, unit) = match raw {
+            RawQuantity::Whole(amount) => (RawAmount::Whole(amount).micros()?, None),
+            RawQuantity::Number(amount) => (RawAmount::Number(amount).micros()?, None),
+            RawQuantity::Text(text) => {
+                let text = text.trim();
+                let split = text.find(|c: char| c.is_alphabetic()).unwrap_or(text.len());
+                let (amount, unit) = text.split_at(split);
+                let unit = match unit.trim() {
+                    "" => None,
+                    unit => Some(Unit::try_from(unit.to_string())?),
+                };
+                (parse_amount(amount)?, unit)
+            }
+            RawQuantity::Object { amount, unit } => (amount.micros()?, unit),
+        };
+
+        if micros > MAX_AMOUNT * MICROS {
+            return Err(format!("amounts must be at most {MAX_AMOUNT}"));
+        }
+
+        Ok(Quantity { micros, unit })
+    }
+}
+
+/// Thousandths of a gram, millilitre or piece in one of `unit`, with bare
+/// amounts counted as if they were grams.
+fn unit_factor(unit: Option<Unit>) -> u128 {
+    unit.map_or(1000, |unit| unit.base_factor())
+}
 
-    HttpResponse::Ok().body(recipe)
+impl Quantity {
+    fn zero() -> Self {
+        Quantity {
+            micros: 0,
+            unit: None,
+        }
+    }
+
+    /// Amount in billionths of a gram, millilitre or piece, reading a bare
+    /// amount in `unit`. `None` if the two cannot be converted.
+    fn base_amount(&self, unit: Option<Unit>) -> Option<u128> {
+        match (self.unit, unit) {
+            (Some(from), Some(to)) if from.dimension() != to.dimension() => None,
+            (unit, read_as) => Some(self.micros * unit_factor(unit.or(read_as))),
+        }
+    }
+
+    /// The same ingredient holding `base`, the inverse of `base_amount`
+    /// rounded to the nearest millionth.
+    fn with_base_amount(&self, base: u128, read_as: Option<Unit>) -> Quantity {
+        let factor = unit_factor(self.unit.or(read_as));
+        Quantity {
+            micros: base / factor + (base % factor >= factor - factor / 2) as u128,
+            unit: self.unit,
+        }
+    }
+
+    /// The amount as a plain decimal, the form it is stored in.
+    fn decimal(&self) -> String {
+        format!("{}.{:06}", self.micros / MICROS, self.micros % MICROS)
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        let whole = self.micros / MICROS;
+        let amount = match self.micros.is_multiple_of(MICROS) && whole <= u64::MAX as u128 {
+            true => json!(whole as u64),
+            false => json!(self.micros as f64 / MICROS as f64),
+        };
+
+        match self.unit {
+            Some(unit) => json!({ "amount": amount, "unit": unit.symbol() }),
+            None => amount,
+        }
+    }
 }
 
+impl Serialize for Quantity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+/// Rounds away float noise, writing whole numbers as integers.
+fn rounded_json(value: f64) -> serde_json::Value {
+    let value = (value * 1e6).round() / 1e6;
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        json!(value as i64)
+    } else {
+        json!(value)
+    }
+}
+
+// slack for floating point error when comparing batch values
+const EPSILON: f64 = 1e-9;
+
 #[derive(Deserialize)]
 struct BakeOrder {
-    recipe: serde_json::Value,
-    pantry: serde_json::Value,
+    recipe: HashMap<String, Quantity>,
+    pantry: HashMap<String, Quantity>,
 }
 
-#[get("/7/bake")]
-pub async fn day_7_bake(req: HttpRequest) -> Result<impl Responder> {
-    let cookie_header = req.headers().get("Cookie").unwrap();
-    let recipe = decode_cookie_header(&cookie_header);
+struct BakeOutcome {
+    cookies: u64,
+    pantry: HashMap<String, Quantity>,
+    target: Option<u64>,
+    shopping_list: Option<HashMap<String, Quantity>>,
+}
 
-    let bake_order: BakeOrder = serde_json::from_str(recipe.as_str()).unwrap();
-    let recipe = bake_order.recipe.as_object().unwrap();
-    let pantry = bake_order.pantry.as_object().unwrap();
+impl BakeOutcome {
+    fn to_json(&self) -> serde_json::Value {
+        match (self.target, &self.shopping_list) {
+            (Some(target), Some(shopping_list)) => json!({
+                "cookies": self.cookies,
+                "pantry": self.pantry,
+                "target": target,
+                "shopping_list": shopping_list
+            }),
+            _ => json!({
+                "cookies": self.cookies,
+                "pantry": self.pantry
+            }),
+        }
+    }
+}
 
-    let mut calc: Vec<_> = recipe
-        .iter()
+fn bake(
+    recipe: &HashMap<String, Quantity>,
+    pantry: &HashMap<String, Quantity>,
+    target: Option<u64>,
+) -> Result<BakeOutcome, RecipeError> {
+    // what the pantry has of every recipe ingredient and what one cookie
+    // needs, both in base units, with the unit a bare amount on either side
+    // is read in
+    let mut stock: HashMap<&String, (u128, u128, Option<Unit>)> = HashMap::new();
+    for (item, qty) in recipe {
+        let have = pantry.get(item).copied().unwrap_or(Quantity::zero());
+        let incompatible = || RecipeError::IncompatibleUnits {
+            ingredient: item.clone(),
+        };
+        let available = have.base_amount(qty.unit).ok_or_else(incompatible)?;
+        let needed = qty.base_amount(have.unit).ok_or_else(incompatible)?;
+        stock.insert(item, (available, needed, have.unit.or(qty.unit)));
+    }
+
+    let max_cookies = stock
+        .values()
         // get rid of ingredients that are listed but have 0 qty
-        .filter(|(_item, qty)| qty.as_i64().unwrap() > 0)
+        .filter(|(_, needed, _)| *needed > 0)
+        .map(|(available, needed, _)| available / needed)
+        .min()
+        .unwrap_or(0)
+        .min(u64::MAX as u128) as u64;
+
+    let pantry_balances = pantry
+        .iter()
         .map(|(item, qty)| {
-            (
-                item,
-                pantry
+            let remaining = match stock.get(item) {
+                Some(&(available, needed, read_as)) => {
+                    qty.with_base_amount(available - needed * max_cookies as u128, read_as)
+                }
+                None => *qty,
+            };
+
+            (item.clone(), remaining)
+        })
+        .collect();
+
+    let shopping_list = target.map(|target| {
+        recipe
+            .iter()
+            .filter_map(|(item, qty)| {
+                let (available, needed, read_as) = stock[item];
+                let missing = needed
+                    .saturating_mul(target as u128)
+                    .saturating_sub(available);
+                if missing == 0 {
+                    return None;
+                }
+
+                // a bare recipe amount is written in the pantry's unit, and
+                // anything short of a millionth is rounded up
+                let unit = qty.unit.or(read_as);
+                let factor = unit_factor(unit);
+                let micros = missing / factor + (missing % factor > 0) as u128;
+                Some((item.clone(), Quantity { micros, unit }))
+            })
+            .collect()
+    });
+
+    Ok(BakeOutcome {
+        cookies: max_cookies,
+        pantry: pantry_balances,
+        target,
+        shopping_list,
+    })
+}
+
+#[derive(Deserialize)]
+struct BakeQuery {
+    target: Option<u64>,
+    recipe: Option<String>,
+    version: Option<i32>,
+    pantry: Option<i32>,
+    #[serde(default)]
+    deduct: bool,
+}
+
+/// Bakes either the recipe and pantry carried in the recipe cookie, or a
+/// stored recipe against a stored pantry when `recipe` and `pantry` are given.
+/// Nothing is deducted here, that takes `POST /7/pantries/{id}/bake`.
+#[get("/7/bake")]
+pub async fn day_7_bake(
+    req: HttpRequest,
+    query: web::Query<BakeQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let query = query.into_inner();
+
+    let (name, pantry_id) = match (query.recipe, query.pantry) {
+        (Some(name), Some(pantry_id)) => (name, pantry_id),
+        (None, None) => {
+            let recipe = decode_recipe_cookie(
+                &req,
+                &data.secrets.recipe_cookie_key,
+                data.secrets.signed_recipes_only,
+            )?;
+            let bake_order: BakeOrder =
+                serde_json::from_str(recipe.as_str()).map_err(RecipeError::InvalidRecipe)?;
+            let outcome = bake(&bake_order.recipe, &bake_order.pantry, query.target)?;
+
+            return Ok(web::Json(outcome.to_json()));
+        }
+        _ => return Err(RecipeError::IncompleteStoredBake),
+    };
+    if query.deduct {
+        return Err(RecipeError::DeductNeedsPost);
+    }
+
+    let res = bake_stored(&data, &name, query.version, pantry_id, query.target, false).await?;
+
+    Ok(web::Json(res))
+}
+
+#[derive(Deserialize)]
+struct StoredBakeQuery {
+    recipe: String,
+    version: Option<i32>,
+    target: Option<u64>,
+}
+
+/// Bakes a stored recipe against the pantry and takes what was used out of it.
+#[post("/7/pantries/{id}/bake")]
+pub async fn day_7_bake_pantry(
+    id: web::Path<i32>,
+    query: web::Query<StoredBakeQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let query = query.into_inner();
+
+    let res = bake_stored(
+        &data,
+        &query.recipe,
+        query.version,
+        id.into_inner(),
+        query.target,
+        true,
+    )
+    .await?;
+
+    Ok(web::Json(res))
+}
+
+async fn bake_stored(
+    data: &AppState,
+    name: &str,
+    version: Option<i32>,
+    pantry_id: i32,
+    target: Option<u64>,
+    deduct: bool,
+) -> Result<serde_json::Value, RecipeError> {
+    let mut tx = data.pool.begin().await?;
+
+    let (version, recipe) = load_recipe(&mut tx, name, version).await?;
+    let (owner, pantry) = load_pantry(&mut tx, pantry_id, deduct).await?;
+    let outcome = bake(&recipe, &pantry, target)?;
+
+    if deduct {
+        store_pantry_items(&mut tx, pantry_id, &outcome.pantry).await?;
+    }
+    tx.commit().await?;
+
+    let mut res = outcome.to_json();
+    res["recipe"] = json!({ "name": name, "version": version });
+    res["pantry_id"] = json!(pantry_id);
+    res["owner"] = json!(owner);
+    res["deducted"] = json!(deduct);
+
+    Ok(res)
+}
+
+fn default_one() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct ProductionRecipe {
+    ingredients: HashMap<String, Quantity>,
+    #[serde(default = "default_one")]
+    cookies_per_batch: f64,
+    /// Value of a single cookie from this recipe.
+    #[serde(default = "default_one")]
+    value: f64,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Objective {
+    #[default]
+    Cookies,
+    Value,
+}
+
+#[derive(Deserialize)]
+struct ProductionOrder {
+    recipes: HashMap<String, ProductionRecipe>,
+    pantry: HashMap<String, Quantity>,
+    #[serde(default)]
+    objective: Objective,
+}
+
+// upper limit on explored batch mixes before settling for the best found so far
+const MAX_SEARCH_NODES: usize = 2_000_000;
+
+/// Integer program `max sum(weight[r] * x[r])` subject to
+/// `sum(usage[r][i] * x[r]) <= capacity[i]`, solved with depth-first branch
+/// and bound.
+struct BatchSearch {
+    usage: Vec<Vec<u128>>,
+    weight: Vec<f64>,
+    best_value: f64,
+    best: Vec<u64>,
+    current: Vec<u64>,
+    nodes: usize,
+}
+
+impl BatchSearch {
+    fn solve(usage: Vec<Vec<u128>>, weight: Vec<f64>, mut capacity: Vec<u128>) -> Self {
+        let mut search = BatchSearch {
+            best: vec![0; weight.len()],
+            current: vec![0; weight.len()],
+            usage,
+            weight,
+            best_value: 0.0,
+            nodes: 0,
+        };
+        search.search(0, &mut capacity, 0.0);
+
+        search
+    }
+
+    /// Whether the search finished rather than stopping at the node limit.
+    fn optimal(&self) -> bool {
+        self.nodes <= MAX_SEARCH_NODES
+    }
+
+    fn max_batches(&self, recipe: usize, capacity: &[u128]) -> u64 {
+        self.usage[recipe]
+            .iter()
+            .zip(capacity)
+            .filter(|(used, _)| **used > 0)
+            .map(|(used, left)| left / used)
+            .min()
+            .unwrap_or(0)
+            .min(u64::MAX as u128) as u64
+    }
+
+    /// Optimistic value of recipes `from..` given what is left in the pantry.
+    fn bound(&self, from: usize, capacity: &[u128]) -> f64 {
+        let recipes = from..self.weight.len();
+
+        // each recipe on its own
+        let mut bound: f64 = recipes
+            .clone()
+            .map(|r| self.weight[r] * self.max_batches(r, capacity) as f64)
+            .sum();
+
+        // an ingredient every remaining recipe needs caps the total by its
+        // best value per unit
+        for (i, left) in capacity.iter().enumerate() {
+            if let Some(ratio) = self.best_ratio(from, i) {
+                bound = bound.min(ratio * *left as f64);
+            }
+        }
+
+        bound
+    }
+
+    /// Best value per unit of ingredient `i` among recipes `from..`, if all of
+    /// them use it.
+    fn best_ratio(&self, from: usize, i: usize) -> Option<f64> {
+        (from..self.weight.len()).try_fold(0.0_f64, |best, r| {
+            let used = self.usage[r][i];
+            (used > 0).then(|| best.max(self.weight[r] / used as f64))
+        })
+    }
+
+    fn search(&mut self, recipe: usize, capacity: &mut [u128], value: f64) {
+        self.nodes += 1;
+
+        if value > self.best_value + EPSILON {
+            self.best_value = value;
+            self.best = self.current.clone();
+        }
+        if recipe == self.weight.len()
+            || self.nodes > MAX_SEARCH_NODES
+            || value + self.bound(recipe, capacity) <= self.best_value + EPSILON
+        {
+            return;
+        }
+
+        let most = self.max_batches(recipe, capacity);
+
+        // with values never negative, the last recipe takes all it can
+        if recipe + 1 == self.weight.len() {
+            self.current[recipe] = most;
+            self.search(
+                recipe + 1,
+                capacity,
+                value + self.weight[recipe] * most as f64,
+            );
+            self.current[recipe] = 0;
+            return;
+        }
+
+        // Bounds on what the later recipes add that only shrink as this one
+        // takes fewer batches, so once they cannot beat the best found the
+        // loop is done: the bound given everything left now, and the cap from
+        // an ingredient they all need where this recipe is worth at least as
+        // much per unit.
+        let rest_bound = self.bound(recipe + 1, capacity);
+        let caps: Vec<(u128, u128, f64)> = capacity
+            .iter()
+            .enumerate()
+            .filter_map(|(i, left)| {
+                let ratio = self.best_ratio(recipe + 1, i)?;
+                let used = self.usage[recipe][i];
+                (self.weight[recipe] >= ratio * used as f64).then_some((*left, used, ratio))
+            })
+            .collect();
+
+        // try the largest batch count first so good solutions are found early
+        for batches in (0..=most).rev() {
+            let rest = caps
+                .iter()
+                .map(|(left, used, ratio)| ratio * (left - used * batches as u128) as f64)
+                .fold(rest_bound, f64::min);
+            if self.nodes > MAX_SEARCH_NODES
+                || value + self.weight[recipe] * batches as f64 + rest <= self.best_value + EPSILON
+            {
+                break;
+            }
+
+            for (left, used) in capacity.iter_mut().zip(&self.usage[recipe]) {
+                *left -= used * batches as u128;
+            }
+            self.current[recipe] = batches;
+
+            self.search(
+                recipe + 1,
+                capacity,
+                value + self.weight[recipe] * batches as f64,
+            );
+
+            for (left, used) in capacity.iter_mut().zip(&self.usage[recipe]) {
+                *left += used * batches as u128;
+            }
+        }
+        self.current[recipe] = 0;
+    }
+}
+
+#[post("/7/optimise")]
+pub async fn day_7_optimise(
+    order: web::Json<ProductionOrder>,
+) -> Result<impl Responder, RecipeError> {
+    let order = order.into_inner();
+
+    // bare amounts of an ingredient are read in the pantry's unit for it, or
+    // in the first recipe's unit when the pantry gives none
+    let mut ingredients: Vec<(&String, Option<Unit>)> = Vec::new();
+    for recipe in order.recipes.values() {
+        for (item, qty) in &recipe.ingredients {
+            if qty.micros > 0 && !ingredients.iter().any(|(name, _)| *name == item) {
+                let unit = order
+                    .pantry
                     .get(item)
-                    .unwrap_or(&serde_json::Value::from(0))
-                    .as_u64()
-                    .unwrap()
-                    / qty.as_u64().unwrap(),
-            )
+                    .and_then(|have| have.unit)
+                    .or(qty.unit);
+                ingredients.push((item, unit));
+            }
+        }
+    }
+
+    let incompatible = |item: &String| RecipeError::IncompatibleUnits {
+        ingredient: item.clone(),
+    };
+
+    let capacity = ingredients
+        .iter()
+        .map(|(item, unit)| {
+            order
+                .pantry
+                .get(*item)
+                .unwrap_or(&Quantity::zero())
+                .base_amount(*unit)
+                .ok_or_else(|| incompatible(item))
         })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // best value per batch first, which lets the search prune sooner
+    let mut recipes: Vec<(&String, &ProductionRecipe)> = order.recipes.iter().collect();
+    let batch_weight = |recipe: &ProductionRecipe| match order.objective {
+        Objective::Cookies => recipe.cookies_per_batch,
+        Objective::Value => recipe.cookies_per_batch * recipe.value,
+    };
+    recipes.sort_by(|a, b| batch_weight(b.1).total_cmp(&batch_weight(a.1)));
+
+    let usage = recipes
+        .iter()
+        .map(|(name, recipe)| {
+            let usage = ingredients
+                .iter()
+                .map(|(item, unit)| match recipe.ingredients.get(*item) {
+                    Some(qty) => qty.base_amount(*unit).ok_or_else(|| incompatible(item)),
+                    None => Ok(0),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if batch_weight(recipe) > 0.0 && usage.iter().all(|used| *used == 0) {
+                return Err(RecipeError::EmptyRecipe {
+                    recipe: name.to_string(),
+                });
+            }
+            Ok(usage)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let weight: Vec<f64> = recipes
+        .iter()
+        .map(|(_, recipe)| batch_weight(recipe).max(0.0))
         .collect();
 
-    calc.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    // the search is bounded by MAX_SEARCH_NODES, but can still take a while
+    let search = {
+        let capacity = capacity.clone();
+        web::block(move || BatchSearch::solve(usage, weight, capacity))
+            .await
+            .map_err(|_| RecipeError::Interrupted)?
+    };
 
-    let max_cookies = calc.get(0).unwrap().1;
+    let batches: HashMap<_, _> = recipes
+        .iter()
+        .zip(&search.best)
+        .map(|((name, _), batches)| (*name, *batches))
+        .collect();
+    let cookies: f64 = recipes
+        .iter()
+        .zip(&search.best)
+        .map(|((_, recipe), batches)| recipe.cookies_per_batch * *batches as f64)
+        .sum();
+    let value: f64 = recipes
+        .iter()
+        .zip(&search.best)
+        .map(|((_, recipe), batches)| recipe.cookies_per_batch * recipe.value * *batches as f64)
+        .sum();
 
-    let pantry_balances: HashMap<_, _> = pantry
-        .into_iter()
+    let pantry_balances: HashMap<_, _> = order
+        .pantry
+        .iter()
         .map(|(item, qty)| {
-            (
-                item,
-                qty.as_u64().unwrap_or_default()
-                    - (max_cookies
-                        * &recipe
-                            .get(item)
-                            .unwrap_or(&serde_json::Value::from(0))
-                            .as_u64()
-                            .unwrap()),
-            )
+            let Some((i, (_, read_as))) = ingredients
+                .iter()
+                .enumerate()
+                .find(|(_, (name, _))| *name == item)
+            else {
+                return (item, *qty);
+            };
+            let used: u128 = search
+                .usage
+                .iter()
+                .zip(&search.best)
+                .map(|(usage, batches)| usage[i] * *batches as u128)
+                .sum();
+
+            (item, qty.with_base_amount(capacity[i] - used, *read_as))
         })
         .collect();
 
     Ok(web::Json(json!({
-        "cookies": max_cookies,
-        "pantry": pantry_balances
+        "batches": batches,
+        "cookies": rounded_json(cookies),
+        "value": rounded_json(value),
+        "pantry": pantry_balances,
+        "optimal": search.optimal()
+    })))
+}
+
+#[derive(FromRow)]
+struct IngredientRow {
+    ingredient: String,
+    amount: String,
+    unit: Option<String>,
+}
+
+fn ingredients_from_rows(rows: Vec<IngredientRow>) -> HashMap<String, Quantity> {
+    rows.into_iter()
+        .map(|row| {
+            let quantity = Quantity {
+                // only amounts written by `Quantity::decimal` are ever stored
+                micros: parse_micros(&row.amount).unwrap_or_default(),
+                // only symbols written by `Unit::symbol` are ever stored
+                unit: row.unit.and_then(|unit| Unit::try_from(unit).ok()),
+            };
+            (row.ingredient, quantity)
+        })
+        .collect()
+}
+
+/// Loads a stored recipe at `version`, or at its current version.
+async fn load_recipe(
+    conn: &mut PgConnection,
+    name: &str,
+    version: Option<i32>,
+) -> Result<(i32, HashMap<String, Quantity>), RecipeError> {
+    let not_found = || RecipeError::RecipeNotFound {
+        name: name.to_string(),
+    };
+
+    let version = match version {
+        Some(version) => {
+            sqlx::query_scalar::<_, i32>(
+                "SELECT version FROM recipe_versions WHERE recipe_name = $1 AND version = $2",
+            )
+            .bind(name)
+            .bind(version)
+            .fetch_optional(&mut *conn)
+            .await?
+        }
+        None => {
+            sqlx::query_scalar::<_, i32>("SELECT current_version FROM recipes WHERE name = $1")
+                .bind(name)
+                .fetch_optional(&mut *conn)
+                .await?
+        }
+    }
+    .ok_or_else(not_found)?;
+
+    let rows = sqlx::query_as::<_, IngredientRow>(
+        "SELECT ingredient, amount::TEXT AS amount, unit FROM recipe_ingredients WHERE recipe_name = $1 AND version = $2",
+    )
+    .bind(name)
+    .bind(version)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok((version, ingredients_from_rows(rows)))
+}
+
+async fn store_recipe_version(
+    conn: &mut PgConnection,
+    name: &str,
+    version: i32,
+    ingredients: &HashMap<String, Quantity>,
+) -> Result<(), RecipeError> {
+    sqlx::query("INSERT INTO recipe_versions (recipe_name, version) VALUES ($1, $2)")
+        .bind(name)
+        .bind(version)
+        .execute(&mut *conn)
+        .await?;
+
+    for (item, qty) in ingredients {
+        sqlx::query(
+            "INSERT INTO recipe_ingredients (recipe_name, version, ingredient, amount, unit) VALUES ($1, $2, $3, $4::NUMERIC, $5)",
+        )
+        .bind(name)
+        .bind(version)
+        .bind(item)
+        .bind(qty.decimal())
+        .bind(qty.unit.map(|unit| unit.symbol()))
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Loads a stored pantry, locking it for the rest of the transaction when
+/// `for_update` is set.
+async fn load_pantry(
+    conn: &mut PgConnection,
+    id: i32,
+    for_update: bool,
+) -> Result<(String, HashMap<String, Quantity>), RecipeError> {
+    let query = if for_update {
+        "SELECT owner FROM pantries WHERE id = $1 FOR UPDATE"
+    } else {
+        "SELECT owner FROM pantries WHERE id = $1"
+    };
+    let owner = sqlx::query_scalar::<_, String>(query)
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(RecipeError::PantryNotFound { id })?;
+
+    let rows = sqlx::query_as::<_, IngredientRow>(
+        "SELECT ingredient, amount::TEXT AS amount, unit FROM pantry_items WHERE pantry_id = $1",
+    )
+    .bind(id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok((owner, ingredients_from_rows(rows)))
+}
+
+async fn store_pantry_items(
+    conn: &mut PgConnection,
+    id: i32,
+    ingredients: &HashMap<String, Quantity>,
+) -> Result<(), RecipeError> {
+    sqlx::query("DELETE FROM pantry_items WHERE pantry_id = $1")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+    for (item, qty) in ingredients {
+        sqlx::query(
+            "INSERT INTO pantry_items (pantry_id, ingredient, amount, unit) VALUES ($1, $2, $3::NUMERIC, $4)",
+        )
+        .bind(id)
+        .bind(item)
+        .bind(qty.decimal())
+        .bind(qty.unit.map(|unit| unit.symbol()))
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Checks names against the columns they are stored in, which would
+/// otherwise fail as a database error.
+fn check_names<'a>(
+    kind: &'static str,
+    names: impl IntoIterator<Item = &'a String>,
+) -> Result<(), RecipeError> {
+    for name in names {
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(RecipeError::InvalidName { kind });
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct NewRecipe {
+    name: String,
+    ingredients: HashMap<String, Quantity>,
+}
+
+#[derive(Deserialize)]
+struct IngredientList {
+    ingredients: HashMap<String, Quantity>,
+}
+
+#[derive(FromRow, Serialize)]
+struct RecipeSummary {
+    name: String,
+    #[sqlx(rename = "current_version")]
+    version: i32,
+}
+
+#[post("/7/recipes")]
+pub async fn day_7_create_recipe(
+    recipe: web::Json<NewRecipe>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let recipe = recipe.into_inner();
+    check_names("recipe", [&recipe.name])?;
+    check_names("ingredient", recipe.ingredients.keys())?;
+    let mut tx = data.pool.begin().await?;
+
+    let created = sqlx::query(
+        "INSERT INTO recipes (name, current_version) VALUES ($1, 1) ON CONFLICT DO NOTHING",
+    )
+    .bind(&recipe.name)
+    .execute(&mut *tx)
+    .await?;
+    if created.rows_affected() == 0 {
+        return Err(RecipeError::RecipeExists { name: recipe.name });
+    }
+
+    store_recipe_version(&mut tx, &recipe.name, 1, &recipe.ingredients).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(json!({
+        "name": recipe.name,
+        "version": 1,
+        "ingredients": recipe.ingredients
+    })))
+}
+
+#[get("/7/recipes")]
+pub async fn day_7_list_recipes(data: web::Data<AppState>) -> Result<impl Responder, RecipeError> {
+    let recipes = sqlx::query_as::<_, RecipeSummary>(
+        "SELECT name, current_version FROM recipes ORDER BY name",
+    )
+    .fetch_all(&data.pool)
+    .await?;
+
+    Ok(web::Json(json!(recipes)))
+}
+
+#[derive(Deserialize)]
+struct RecipeQuery {
+    version: Option<i32>,
+}
+
+#[get("/7/recipes/{name}")]
+pub async fn day_7_get_recipe(
+    name: web::Path<String>,
+    query: web::Query<RecipeQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let name = name.into_inner();
+    let mut conn = data.pool.acquire().await?;
+
+    let (version, ingredients) = load_recipe(&mut conn, &name, query.version).await?;
+
+    Ok(web::Json(json!({
+        "name": name,
+        "version": version,
+        "ingredients": ingredients
+    })))
+}
+
+#[get("/7/recipes/{name}/versions")]
+pub async fn day_7_recipe_versions(
+    name: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let name = name.into_inner();
+
+    let versions = sqlx::query_scalar::<_, i32>(
+        "SELECT version FROM recipe_versions WHERE recipe_name = $1 ORDER BY version",
+    )
+    .bind(&name)
+    .fetch_all(&data.pool)
+    .await?;
+    if versions.is_empty() {
+        return Err(RecipeError::RecipeNotFound { name });
+    }
+
+    Ok(web::Json(json!({
+        "name": name,
+        "versions": versions
+    })))
+}
+
+/// Stores a new version of the recipe, older versions stay readable.
+#[put("/7/recipes/{name}")]
+pub async fn day_7_update_recipe(
+    name: web::Path<String>,
+    recipe: web::Json<IngredientList>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let name = name.into_inner();
+    let recipe = recipe.into_inner();
+    check_names("ingredient", recipe.ingredients.keys())?;
+    let mut tx = data.pool.begin().await?;
+
+    let version = sqlx::query_scalar::<_, i32>(
+        "UPDATE recipes SET current_version = current_version + 1 WHERE name = $1 RETURNING current_version",
+    )
+    .bind(&name)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| RecipeError::RecipeNotFound { name: name.clone() })?;
+
+    store_recipe_version(&mut tx, &name, version, &recipe.ingredients).await?;
+    tx.commit().await?;
+
+    Ok(web::Json(json!({
+        "name": name,
+        "version": version,
+        "ingredients": recipe.ingredients
     })))
 }
+
+#[delete("/7/recipes/{name}")]
+pub async fn day_7_delete_recipe(
+    name: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let name = name.into_inner();
+
+    let deleted = sqlx::query("DELETE FROM recipes WHERE name = $1")
+        .bind(&name)
+        .execute(&data.pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(RecipeError::RecipeNotFound { name });
+    }
+
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(Deserialize)]
+struct NewPantry {
+    owner: String,
+    #[serde(default)]
+    ingredients: HashMap<String, Quantity>,
+}
+
+#[derive(FromRow, Serialize)]
+struct PantrySummary {
+    id: i32,
+    owner: String,
+}
+
+#[post("/7/pantries")]
+pub async fn day_7_create_pantry(
+    pantry: web::Json<NewPantry>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let pantry = pantry.into_inner();
+    check_names("owner", [&pantry.owner])?;
+    check_names("ingredient", pantry.ingredients.keys())?;
+    let mut tx = data.pool.begin().await?;
+
+    let id = sqlx::query_scalar::<_, i32>("INSERT INTO pantries (owner) VALUES ($1) RETURNING id")
+        .bind(&pantry.owner)
+        .fetch_one(&mut *tx)
+        .await?;
+    store_pantry_items(&mut tx, id, &pantry.ingredients).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(json!({
+        "id": id,
+        "owner": pantry.owner,
+        "ingredients": pantry.ingredients
+    })))
+}
+
+#[derive(Deserialize)]
+struct PantryQuery {
+    owner: Option<String>,
+}
+
+#[get("/7/pantries")]
+pub async fn day_7_list_pantries(
+    query: web::Query<PantryQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let pantries = sqlx::query_as::<_, PantrySummary>(
+        "SELECT id, owner FROM pantries WHERE $1::VARCHAR IS NULL OR owner = $1 ORDER BY id",
+    )
+    .bind(&query.owner)
+    .fetch_all(&data.pool)
+    .await?;
+
+    Ok(web::Json(json!(pantries)))
+}
+
+#[get("/7/pantries/{id}")]
+pub async fn day_7_get_pantry(
+    id: web::Path<i32>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let id = id.into_inner();
+    let mut conn = data.pool.acquire().await?;
+
+    let (owner, ingredients) = load_pantry(&mut conn, id, false).await?;
+
+    Ok(web::Json(json!({
+        "id": id,
+        "owner": owner,
+        "ingredients": ingredients
+    })))
+}
+
+/// Replaces everything in the pantry with the given ingredients.
+#[put("/7/pantries/{id}")]
+pub async fn day_7_update_pantry(
+    id: web::Path<i32>,
+    pantry: web::Json<IngredientList>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let id = id.into_inner();
+    let pantry = pantry.into_inner();
+    check_names("ingredient", pantry.ingredients.keys())?;
+    let mut tx = data.pool.begin().await?;
+
+    let (owner, _) = load_pantry(&mut tx, id, true).await?;
+    store_pantry_items(&mut tx, id, &pantry.ingredients).await?;
+    tx.commit().await?;
+
+    Ok(web::Json(json!({
+        "id": id,
+        "owner": owner,
+        "ingredients": pantry.ingredients
+    })))
+}
+
+#[delete("/7/pantries/{id}")]
+pub async fn day_7_delete_pantry(
+    id: web::Path<i32>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, RecipeError> {
+    let id = id.into_inner();
+
+    let deleted = sqlx::query("DELETE FROM pantries WHERE id = $1")
+        .bind(id)
+        .execute(&data.pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(RecipeError::PantryNotFound { id });
+    }
+
+    Ok(HttpResponse::Ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantity(value: serde_json::Value) -> Result<Quantity, String> {
+        serde_json::from_value(value).map_err(|err| err.to_string())
+    }
+
+    fn ingredients(value: serde_json::Value) -> HashMap<String, Quantity> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn parses_decimals_to_the_millionth() {
+        assert_eq!(parse_micros("2"), Some(2_000_000));
+        assert_eq!(parse_micros("0.25"), Some(250_000));
+        assert_eq!(parse_micros(".5"), Some(500_000));
+        assert_eq!(parse_micros("1.0000005"), Some(1_000_001));
+        assert_eq!(parse_micros("1.0000004"), Some(1_000_000));
+        assert_eq!(parse_micros(""), None);
+        assert_eq!(parse_micros("."), None);
+        assert_eq!(parse_micros("-1"), None);
+        assert_eq!(parse_micros("1e3"), None);
+    }
+
+    #[test]
+    fn parses_fractions_and_mixed_numbers() {
+        assert_eq!(parse_amount("1/2"), Ok(500_000));
+        assert_eq!(parse_amount("2 1/2"), Ok(2_500_000));
+        assert_eq!(parse_amount("1/3"), Ok(333_333));
+        assert_eq!(parse_amount("2/3"), Ok(666_667));
+        assert!(parse_amount("1/0").is_err());
+        assert!(parse_amount("   ").is_err());
+        assert!(parse_amount("a/b").is_err());
+    }
+
+    #[test]
+    fn keeps_large_integers_exact() {
+        let max = quantity(json!(u64::MAX)).unwrap();
+        assert_eq!(max.micros, u64::MAX as u128 * MICROS);
+        assert_eq!(max.to_json(), json!(u64::MAX));
+
+        // one past the last integer an f64 holds exactly
+        let odd = quantity(json!(9_007_199_254_740_993_u64)).unwrap();
+        assert_eq!(odd.to_json(), json!(9_007_199_254_740_993_u64));
+    }
+
+    #[test]
+    fn reads_every_quantity_form() {
+        let grams = |micros| Quantity {
+            micros,
+            unit: Some(Unit::Gram),
+        };
+
+        assert_eq!(quantity(json!(1.5)).unwrap().micros, 1_500_000);
+        assert_eq!(quantity(json!("250 g")), Ok(grams(250_000_000)));
+        assert_eq!(quantity(json!("1/4g")), Ok(grams(250_000)));
+        assert_eq!(
+            quantity(json!({ "amount": "2 1/2", "unit": "grams" })),
+            Ok(grams(2_500_000))
+        );
+        assert_eq!(
+            quantity(json!({ "amount": 3, "unit": "g" })),
+            Ok(grams(3_000_000))
+        );
+
+        assert!(quantity(json!(-1)).is_err());
+        assert!(quantity(json!(1e30)).is_err());
+        assert!(quantity(json!("3 spoons")).is_err());
+    }
+
+    #[test]
+    fn converts_between_units_of_one_dimension() {
+        let cup = quantity(json!("1 cup")).unwrap();
+        let ml = quantity(json!("236.588 ml")).unwrap();
+        assert_eq!(cup.base_amount(None), ml.base_amount(None));
+
+        let kg = quantity(json!("1 kg")).unwrap();
+        assert_eq!(
+            kg.base_amount(Some(Unit::Gram)),
+            quantity(json!("1000 g")).unwrap().base_amount(None)
+        );
+        assert_eq!(kg.base_amount(Some(Unit::Millilitre)), None);
+
+        // a bare amount takes the unit it is compared against
+        let bare = quantity(json!(2)).unwrap();
+        assert_eq!(
+            bare.base_amount(Some(Unit::Kilogram)),
+            Some(2_000_000_000_000)
+        );
+    }
+
+    #[test]
+    fn bakes_huge_pantries_exactly() {
+        let recipe = ingredients(json!({ "flour": 3, "sugar": 0 }));
+        let pantry = ingredients(json!({ "flour": u64::MAX, "butter": 7 }));
+
+        let outcome = bake(&recipe, &pantry, None).unwrap();
+        assert_eq!(outcome.cookies, u64::MAX / 3);
+        assert_eq!(outcome.pantry["flour"].to_json(), json!(u64::MAX % 3));
+        assert_eq!(outcome.pantry["butter"].to_json(), json!(7));
+    }
+
+    #[test]
+    fn bakes_across_units() {
+        let recipe = ingredients(json!({ "flour": "1/3 cup", "egg": 1 }));
+        let pantry = ingredients(json!({ "flour": "1000 ml", "egg": "12 pcs" }));
+
+        let outcome = bake(&recipe, &pantry, Some(20)).unwrap();
+        assert_eq!(outcome.cookies, 12);
+        assert_eq!(
+            outcome.pantry["egg"],
+            Quantity {
+                micros: 0,
+                unit: Some(Unit::Piece)
+            }
+        );
+        let shopping_list = outcome.shopping_list.unwrap();
+        assert_eq!(
+            shopping_list["egg"].to_json(),
+            json!({ "amount": 8, "unit": "pieces" })
+        );
+        // 20 / 3 cups less 1000 ml, rounded up to the millionth
+        assert_eq!(shopping_list["flour"].micros, 2_439_903);
+    }
+
+    #[test]
+    fn rejects_incompatible_units() {
+        let recipe = ingredients(json!({ "flour": "100 g" }));
+        let pantry = ingredients(json!({ "flour": "1 cup" }));
+
+        assert!(matches!(
+            bake(&recipe, &pantry, None),
+            Err(RecipeError::IncompatibleUnits { .. })
+        ));
+    }
+
+    fn brute_force(usage: &[Vec<u128>], weight: &[f64], capacity: &[u128]) -> f64 {
+        let Some((first, rest)) = usage.split_first() else {
+            return 0.0;
+        };
+
+        let mut best = 0.0_f64;
+        for batches in 0.. {
+            if capacity
+                .iter()
+                .zip(first)
+                .any(|(left, used)| left < &(used * batches))
+            {
+                break;
+            }
+            let remaining: Vec<u128> = capacity
+                .iter()
+                .zip(first)
+                .map(|(left, used)| left - used * batches)
+                .collect();
+            let value = weight[0] * batches as f64 + brute_force(rest, &weight[1..], &remaining);
+            best = best.max(value);
+            if first.iter().all(|used| *used == 0) {
+                break;
+            }
+        }
+
+        best
+    }
+
+    #[test]
+    fn finds_the_best_batch_mix() {
+        let cases = [
+            (
+                vec![vec![3, 1], vec![2, 2], vec![1, 3]],
+                vec![5.0, 4.0, 3.0],
+                vec![17, 13],
+            ),
+            (
+                vec![vec![5, 0], vec![4, 1], vec![0, 3]],
+                vec![7.0, 6.0, 2.0],
+                vec![23, 7],
+            ),
+            // greedy takes the big recipe and strands the rest
+            (vec![vec![6], vec![4]], vec![7.0, 4.5], vec![8]),
+            (
+                vec![vec![2, 2, 1], vec![1, 0, 4], vec![0, 3, 1]],
+                vec![1.0, 1.0, 1.0],
+                vec![9, 9, 9],
+            ),
+        ];
+
+        for (usage, weight, capacity) in cases {
+            let expected = brute_force(&usage, &weight, &capacity);
+            let search = BatchSearch::solve(usage.clone(), weight.clone(), capacity.clone());
+
+            assert!(search.optimal());
+            assert!((search.best_value - expected).abs() < EPSILON);
+            for (i, left) in capacity.iter().enumerate() {
+                let used: u128 = usage
+                    .iter()
+                    .zip(&search.best)
+                    .map(|(usage, batches)| usage[i] * *batches as u128)
+                    .sum();
+                assert!(used <= *left);
+            }
+        }
+    }
+
+    #[test]
+    fn settles_huge_pantries_quickly() {
+        // ten thousand tonnes of flour against 1 g a batch
+        let capacity = vec![10_000_000_000_000_000_000];
+        let search = BatchSearch::solve(vec![vec![1_000_000_000]], vec![1.0], capacity.clone());
+        assert_eq!(search.best, vec![10_000_000_000]);
+        assert!(search.nodes < 10);
+
+        // two recipes competing for the same flour
+        let search = BatchSearch::solve(
+            vec![vec![1_000_000_000], vec![2_000_000_000]],
+            vec![1.0, 1.5],
+            capacity,
+        );
+        assert_eq!(search.best, vec![10_000_000_000, 0]);
+        assert!(search.nodes < 10);
+    }
+
+    #[test]
+    fn stops_at_the_node_limit() {
+        // the first recipe is worth less per gram, so nothing prunes its
+        // batch counts and only the node limit ends the search
+        let search = BatchSearch::solve(
+            vec![vec![2_000_000_000], vec![1_000_000_000]],
+            vec![1.0, 1.0],
+            vec![10_000_000_000_000_000_000],
+        );
+
+        assert!(!search.optimal());
+        assert!(search.nodes <= MAX_SEARCH_NODES + 2);
+        assert!(search.best_value >= 5_000_000_000.0);
+    }
+}
@@ -1,38 +1,692 @@
-use actix_web::{get, web, HttpResponse, Responder};
-use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::OnceLock,
+};
+
+use actix_web::{
+    error, get,
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse, Responder,
+};
+use async_trait::async_trait;
+use derive_more::{Display, Error};
+use futures_util::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::AppState;
+
+/// A Pokémon in pokeapi's units: height in decimetres, weight in hectograms.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Pokemon {
+    id: u32,
+    name: String,
+    height: u32,
+    weight: u32,
+    types: Vec<String>,
+    stats: BTreeMap<String, u32>,
+}
+
+impl Pokemon {
+    fn weight_kg(&self) -> f32 {
+        self.weight as f32 / 10.0
+    }
+
+    fn height_m(&self) -> f32 {
+        self.height as f32 / 10.0
+    }
+}
+
+#[derive(Debug, Display, Error)]
+pub enum PokemonError {
+    #[display(fmt = "no pokémon with pokédex number {}", _0)]
+    NotFound(#[error(not(source))] u32),
+
+    #[display(fmt = "pokémon lookup failed: {}", _0)]
+    Upstream(reqwest::Error),
+
+    #[display(fmt = "{}", _0)]
+    InvalidParameter(#[error(not(source))] String),
+}
+
+impl error::ResponseError for PokemonError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            PokemonError::NotFound(_) => StatusCode::NOT_FOUND,
+            PokemonError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            PokemonError::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[async_trait]
+pub trait PokemonProvider: Send + Sync {
+    async fn pokemon(&self, id: u32) -> Result<Pokemon, PokemonError>;
+}
+
+#[derive(Deserialize)]
+struct ApiNamed {
+    name: String,
+}
 
 #[derive(Deserialize)]
-struct Pokemon {
-    weight: f32,
+struct ApiType {
+    #[serde(rename = "type")]
+    kind: ApiNamed,
 }
 
-async fn get_pokemon_by_id(id: u32) -> Pokemon {
-    let pokemon = reqwest::get(format!("https://pokeapi.co/api/v2/pokemon/{}", id))
-        .await
-        .expect("make request");
-    let pokemon = pokemon.json::<Pokemon>().await.expect("parse json");
+#[derive(Deserialize)]
+struct ApiStat {
+    base_stat: u32,
+    stat: ApiNamed,
+}
 
-    pokemon
+#[derive(Deserialize)]
+struct ApiPokemon {
+    id: u32,
+    name: String,
+    height: u32,
+    weight: u32,
+    types: Vec<ApiType>,
+    stats: Vec<ApiStat>,
+}
+
+impl From<ApiPokemon> for Pokemon {
+    fn from(pokemon: ApiPokemon) -> Self {
+        Pokemon {
+            id: pokemon.id,
+            name: pokemon.name,
+            height: pokemon.height,
+            weight: pokemon.weight,
+            types: pokemon.types.into_iter().map(|t| t.kind.name).collect(),
+            stats: pokemon
+                .stats
+                .into_iter()
+                .map(|s| (s.stat.name, s.base_stat))
+                .collect(),
+        }
+    }
+}
+
+/// Looks pokémon up on https://pokeapi.co.
+pub struct PokeApiProvider {
+    client: reqwest::Client,
+}
+
+impl PokeApiProvider {
+    pub fn new() -> Self {
+        PokeApiProvider {
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl PokemonProvider for PokeApiProvider {
+    async fn pokemon(&self, id: u32) -> Result<Pokemon, PokemonError> {
+        let res = self
+            .client
+            .get(format!("https://pokeapi.co/api/v2/pokemon/{}", id))
+            .send()
+            .await
+            .map_err(PokemonError::Upstream)?;
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(PokemonError::NotFound(id));
+        }
+
+        let pokemon = res
+            .error_for_status()
+            .map_err(PokemonError::Upstream)?
+            .json::<ApiPokemon>()
+            .await
+            .map_err(PokemonError::Upstream)?;
+
+        Ok(pokemon.into())
+    }
+}
+
+/// Serves pokémon from a JSON dataset, so nothing needs the network.
+pub struct LocalProvider {
+    pokemon: HashMap<u32, Pokemon>,
+}
+
+impl LocalProvider {
+    /// The small dataset that ships with the crate.
+    pub fn bundled() -> Self {
+        LocalProvider::from_json(include_str!("../fixtures/pokemon.json"))
+            .expect("parse bundled pokemon dataset")
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let dataset = std::fs::read_to_string(path)?;
+
+        Ok(LocalProvider::from_json(&dataset)?)
+    }
+
+    fn from_json(dataset: &str) -> Result<Self, serde_json::Error> {
+        let pokemon: Vec<Pokemon> = serde_json::from_str(dataset)?;
+
+        Ok(LocalProvider {
+            pokemon: pokemon.into_iter().map(|p| (p.id, p)).collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl PokemonProvider for LocalProvider {
+    async fn pokemon(&self, id: u32) -> Result<Pokemon, PokemonError> {
+        self.pokemon
+            .get(&id)
+            .cloned()
+            .ok_or(PokemonError::NotFound(id))
+    }
 }
 
 #[get("/8/weight/{pokedex_number}")]
-pub async fn day_8_weight(path: web::Path<u32>) -> impl Responder {
+pub async fn day_8_weight(
+    path: web::Path<u32>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PokemonError> {
     let pokedex_number = path.into_inner();
 
-    let pokemon = get_pokemon_by_id(pokedex_number).await;
+    let pokemon = data.pokemon.pokemon(pokedex_number).await?;
 
-    HttpResponse::Ok().body((pokemon.weight / 10.0).to_string())
+    Ok(HttpResponse::Ok().body(pokemon.weight_kg().to_string()))
 }
 
 #[get("/8/drop/{pokedex_number}")]
-pub async fn day_8_drop(path: web::Path<u32>) -> impl Responder {
+pub async fn day_8_drop(
+    path: web::Path<u32>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PokemonError> {
     let pokedex_number = path.into_inner();
 
-    let pokemon = get_pokemon_by_id(pokedex_number).await;
+    let pokemon = data.pokemon.pokemon(pokedex_number).await?;
 
     let time = f32::sqrt(2.0 * 10.0 / 9.825);
     let velocity = 9.825 * time;
     let momentum: f32 = (pokemon.weight as f32) * velocity / 10.0;
 
-    HttpResponse::Ok().body(momentum.to_string())
+    Ok(HttpResponse::Ok().body(momentum.to_string()))
+}
+
+#[get("/8/pokemon/{pokedex_number}")]
+pub async fn day_8_pokemon(
+    path: web::Path<u32>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PokemonError> {
+    let pokedex_number = path.into_inner();
+
+    let pokemon = data.pokemon.pokemon(pokedex_number).await?;
+
+    Ok(web::Json(json!({
+        "id": pokemon.id,
+        "name": pokemon.name,
+        "height": pokemon.height_m(),
+        "weight": pokemon.weight_kg(),
+        "types": pokemon.types,
+        "stats": pokemon.stats
+    })))
+}
+
+fn default_drop_height() -> f64 {
+    10.0
+}
+
+fn default_gravity() -> f64 {
+    9.825
+}
+
+fn default_air_density() -> f64 {
+    1.225
+}
+
+fn default_stopping_distance() -> f64 {
+    0.1
+}
+
+#[derive(Deserialize)]
+struct DropParams {
+    /// Metres fallen before impact.
+    #[serde(default = "default_drop_height")]
+    height: f64,
+    /// m/s².
+    #[serde(default = "default_gravity")]
+    gravity: f64,
+    #[serde(default)]
+    drag_coefficient: f64,
+    /// m², defaults to a disc as wide as the pokémon is tall.
+    cross_section: Option<f64>,
+    /// kg/m³.
+    #[serde(default = "default_air_density")]
+    air_density: f64,
+    /// Metres the pokémon takes to come to rest, used to estimate impact force.
+    #[serde(default = "default_stopping_distance")]
+    stopping_distance: f64,
+}
+
+struct Fall {
+    time: f64,
+    velocity: f64,
+}
+
+// integration steps used for a fall, whatever its length
+const DROP_STEPS: f64 = 10_000.0;
+
+/// Drops a mass `height` metres from rest, slowed by quadratic drag
+/// `a = g - k v²` where `k = ρ Cd A / 2m`. Integrated with RK4 and the last
+/// step interpolated to land exactly on the ground.
+fn simulate_fall(height: f64, gravity: f64, k: f64) -> Fall {
+    if k == 0.0 {
+        let time = (2.0 * height / gravity).sqrt();
+        return Fall {
+            time,
+            velocity: gravity * time,
+        };
+    }
+
+    // size the step from whichever is slower, free fall or terminal velocity
+    let terminal_velocity = (gravity / k).sqrt();
+    let estimate = (2.0 * height / gravity)
+        .sqrt()
+        .max(height / terminal_velocity);
+    let dt = estimate / DROP_STEPS;
+
+    let accel = |v: f64| gravity - k * v * v;
+    let (mut time, mut fallen, mut velocity) = (0.0, 0.0, 0.0);
+
+    loop {
+        let (k1x, k1v) = (velocity, accel(velocity));
+        let (k2x, k2v) = (velocity + 0.5 * dt * k1v, accel(velocity + 0.5 * dt * k1v));
+        let (k3x, k3v) = (velocity + 0.5 * dt * k2v, accel(velocity + 0.5 * dt * k2v));
+        let (k4x, k4v) = (velocity + dt * k3v, accel(velocity + dt * k3v));
+
+        let next_fallen = fallen + dt / 6.0 * (k1x + 2.0 * k2x + 2.0 * k3x + k4x);
+        let next_velocity = velocity + dt / 6.0 * (k1v + 2.0 * k2v + 2.0 * k3v + k4v);
+
+        if next_fallen >= height {
+            let fraction = (height - fallen) / (next_fallen - fallen);
+            return Fall {
+                time: time + fraction * dt,
+                velocity: velocity + fraction * (next_velocity - velocity),
+            };
+        }
+
+        time += dt;
+        fallen = next_fallen;
+        velocity = next_velocity;
+    }
+}
+
+#[get("/8/physics/{pokedex_number}")]
+pub async fn day_8_physics(
+    path: web::Path<u32>,
+    params: web::Query<DropParams>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PokemonError> {
+    let pokedex_number = path.into_inner();
+    let params = params.into_inner();
+
+    let invalid = |reason: &str| Err(PokemonError::InvalidParameter(reason.to_string()));
+    let positive = |value: f64| value.is_finite() && value > 0.0;
+    if !positive(params.height) {
+        return invalid("height must be a positive number");
+    }
+    if !positive(params.gravity) {
+        return invalid("gravity must be a positive number");
+    }
+    if !params.drag_coefficient.is_finite() || params.drag_coefficient < 0.0 {
+        return invalid("drag_coefficient must not be negative");
+    }
+    if !positive(params.air_density) {
+        return invalid("air_density must be a positive number");
+    }
+    if !positive(params.stopping_distance) {
+        return invalid("stopping_distance must be a positive number");
+    }
+    if params.cross_section.is_some_and(|area| !positive(area)) {
+        return invalid("cross_section must be a positive number");
+    }
+
+    let pokemon = data.pokemon.pokemon(pokedex_number).await?;
+    let mass = pokemon.weight as f64 / 10.0;
+    let cross_section = params
+        .cross_section
+        .unwrap_or_else(|| std::f64::consts::PI * (pokemon.height as f64 / 20.0).powi(2));
+
+    let k = if params.drag_coefficient > 0.0 {
+        if mass <= 0.0 {
+            return invalid("cannot simulate drag on a weightless pokémon");
+        }
+        params.air_density * params.drag_coefficient * cross_section / (2.0 * mass)
+    } else {
+        0.0
+    };
+
+    let fall = simulate_fall(params.height, params.gravity, k);
+    let momentum = mass * fall.velocity;
+    let kinetic_energy = 0.5 * mass * fall.velocity * fall.velocity;
+
+    Ok(web::Json(json!({
+        "pokemon": pokemon.name,
+        "mass": mass,
+        "height": params.height,
+        "gravity": params.gravity,
+        "drag_coefficient": params.drag_coefficient,
+        "cross_section": cross_section,
+        "air_density": params.air_density,
+        "terminal_velocity": (k > 0.0).then(|| (params.gravity / k).sqrt()),
+        "time": fall.time,
+        "velocity": fall.velocity,
+        "momentum": momentum,
+        "kinetic_energy": kinetic_energy,
+        // work-energy estimate of the average force while stopping
+        "impact_force": kinetic_energy / params.stopping_distance
+    })))
+}
+
+/// Attacking type to the defending types it is not neutral against.
+type TypeChart = BTreeMap<String, BTreeMap<String, f64>>;
+
+fn type_chart() -> &'static TypeChart {
+    static CHART: OnceLock<TypeChart> = OnceLock::new();
+
+    CHART.get_or_init(|| {
+        serde_json::from_str(include_str!("../fixtures/type_chart.json"))
+            .expect("parse bundled type chart")
+    })
+}
+
+fn effectiveness(attacking: &str, defending: &[String]) -> f64 {
+    let chart = type_chart();
+
+    defending
+        .iter()
+        .map(|defending| {
+            chart
+                .get(attacking)
+                .and_then(|against| against.get(defending))
+                .copied()
+                .unwrap_or(1.0)
+        })
+        .product()
+}
+
+#[derive(Default, Serialize)]
+struct TypeMatchup {
+    weak: usize,
+    resist: usize,
+    immune: usize,
+}
+
+const MAX_TEAM_SIZE: usize = 6;
+
+#[derive(Deserialize)]
+struct TeamQuery {
+    /// Comma separated pokédex numbers.
+    ids: String,
+}
+
+#[get("/8/team")]
+pub async fn day_8_team(
+    query: web::Query<TeamQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, PokemonError> {
+    let ids = query
+        .ids
+        .split(',')
+        .map(|id| id.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| PokemonError::InvalidParameter("ids must be pokédex numbers".to_string()))?;
+    if ids.is_empty() || ids.len() > MAX_TEAM_SIZE {
+        return Err(PokemonError::InvalidParameter(format!(
+            "a team has between 1 and {MAX_TEAM_SIZE} pokémon"
+        )));
+    }
+
+    let team = try_join_all(ids.iter().map(|id| data.pokemon.pokemon(*id))).await?;
+
+    let mut stats: BTreeMap<&String, u32> = BTreeMap::new();
+    for pokemon in &team {
+        for (stat, value) in &pokemon.stats {
+            *stats.entry(stat).or_default() += value;
+        }
+    }
+
+    let all_types: Vec<&String> = type_chart().keys().collect();
+
+    // best multiplier the team's own types get against each defending type
+    let coverage: BTreeMap<&String, f64> = all_types
+        .iter()
+        .map(|defending| {
+            let best = team
+                .iter()
+                .flat_map(|pokemon| &pokemon.types)
+                .map(|attacking| effectiveness(attacking, &[defending.to_string()]))
+                .fold(0.0, f64::max);
+            (*defending, best)
+        })
+        .collect();
+
+    let defense: BTreeMap<&String, TypeMatchup> = all_types
+        .iter()
+        .map(|attacking| {
+            let matchup = team
+                .iter()
+                .fold(TypeMatchup::default(), |mut matchup, pokemon| {
+                    let multiplier = effectiveness(attacking, &pokemon.types);
+                    if multiplier == 0.0 {
+                        matchup.immune += 1;
+                    } else if multiplier > 1.0 {
+                        matchup.weak += 1;
+                    } else if multiplier < 1.0 {
+                        matchup.resist += 1;
+                    }
+                    matchup
+                });
+            (*attacking, matchup)
+        })
+        .collect();
+
+    // attacking types more of the team is weak to than can take them
+    let weaknesses: Vec<&&String> = defense
+        .iter()
+        .filter(|(_, matchup)| matchup.weak > matchup.resist + matchup.immune)
+        .map(|(attacking, _)| attacking)
+        .collect();
+
+    Ok(web::Json(json!({
+        "members": team.iter().map(|pokemon| json!({
+            "id": pokemon.id,
+            "name": pokemon.name,
+            "types": pokemon.types
+        })).collect::<Vec<_>>(),
+        "total_weight": team.iter().map(|pokemon| pokemon.weight).sum::<u32>() as f64 / 10.0,
+        "total_height": team.iter().map(|pokemon| pokemon.height).sum::<u32>() as f64 / 10.0,
+        "stats": stats,
+        "base_stat_total": stats.values().sum::<u32>(),
+        "coverage": {
+            "super_effective": coverage.iter().filter(|(_, best)| **best > 1.0).map(|(t, _)| t).collect::<Vec<_>>(),
+            "not_very_effective": coverage.iter().filter(|(_, best)| **best < 1.0).map(|(t, _)| t).collect::<Vec<_>>()
+        },
+        "defense": defense,
+        "weaknesses": weaknesses
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test::TestRequest, App};
+
+    async fn fetch(uri: &str) -> (StatusCode, String) {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState::for_tests()))
+                .service(day_8_weight)
+                .service(day_8_drop)
+                .service(day_8_physics),
+        )
+        .await;
+        let res =
+            actix_web::test::call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        let status = res.status();
+        let body = actix_web::test::read_body(res).await;
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn weighs_from_the_bundled_dataset() {
+        assert_eq!(
+            fetch("/8/weight/25").await,
+            (StatusCode::OK, "6".to_string())
+        );
+        assert_eq!(
+            fetch("/8/weight/143").await,
+            (StatusCode::OK, "460".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn drops_from_the_bundled_dataset() {
+        let (status, body) = fetch("/8/drop/25").await;
+        assert_eq!(status, StatusCode::OK);
+        // 6 kg falling 10 m at g = 9.825
+        let momentum: f64 = body.parse().unwrap();
+        assert!((momentum - 84.10707461325713).abs() < 1e-4, "{}", momentum);
+    }
+
+    #[actix_web::test]
+    async fn answers_unknown_ids_with_not_found() {
+        for uri in ["/8/weight/2", "/8/drop/2"] {
+            let (status, body) = fetch(uri).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+                json!({ "error": "no pokémon with pokédex number 2" })
+            );
+        }
+    }
+
+    #[test]
+    fn reads_a_user_supplied_dataset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pokemon.json");
+        std::fs::write(
+            &path,
+            r#"[{"id": 2, "name": "ivysaur", "height": 10, "weight": 130,
+                "types": ["grass"], "stats": {}}]"#,
+        )
+        .unwrap();
+
+        let provider = LocalProvider::from_file(&path).unwrap();
+        assert_eq!(provider.pokemon[&2].weight_kg(), 13.0);
+        assert!(!provider.pokemon.contains_key(&25));
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(LocalProvider::from_file(&path).is_err());
+    }
+
+    fn close(actual: f64, expected: f64) -> bool {
+        (actual - expected).abs() <= 1e-6 * expected
+    }
+
+    #[test]
+    fn falls_like_the_closed_form_without_drag() {
+        for (height, gravity) in [(10.0_f64, 9.825_f64), (0.01, 9.81), (1000.0, 1.62)] {
+            let time = (2.0 * height / gravity).sqrt();
+            for k in [0.0, 1e-15] {
+                let fall = simulate_fall(height, gravity, k);
+                assert!(close(fall.time, time), "{} {} {}", height, k, fall.time);
+                assert!(close(fall.velocity, gravity * time), "{} {}", height, k);
+            }
+        }
+    }
+
+    #[test]
+    fn falls_like_the_closed_form_with_drag() {
+        // from rest, v = vt tanh(g t / vt) and x = vt² / g ln cosh(g t / vt)
+        for (height, gravity, k) in [
+            (10.0_f64, 9.825_f64, 0.01_f64),
+            (50.0, 9.81, 0.2),
+            (3.0, 9.81, 2.0),
+        ] {
+            let terminal_velocity = (gravity / k).sqrt();
+            let time = terminal_velocity / gravity * (height * k).exp().acosh();
+            let velocity = terminal_velocity * (1.0 - (-2.0 * height * k).exp()).sqrt();
+
+            let fall = simulate_fall(height, gravity, k);
+            assert!(close(fall.time, time), "{} {}", fall.time, time);
+            assert!(
+                close(fall.velocity, velocity),
+                "{} {}",
+                fall.velocity,
+                velocity
+            );
+        }
+    }
+
+    #[test]
+    fn approaches_terminal_velocity() {
+        let (gravity, k): (f64, f64) = (9.81, 0.05);
+        let terminal_velocity = (gravity / k).sqrt();
+
+        let mut last = 0.0;
+        for height in [1.0, 10.0, 100.0, 1000.0] {
+            let velocity = simulate_fall(height, gravity, k).velocity;
+            assert!(
+                last < velocity && velocity < terminal_velocity,
+                "{}",
+                height
+            );
+            last = velocity;
+        }
+        assert!(close(last, terminal_velocity));
+    }
+
+    #[actix_web::test]
+    async fn refuses_invalid_physics_parameters() {
+        for (query, error) in [
+            ("height=0", "height must be a positive number"),
+            ("height=-3", "height must be a positive number"),
+            ("gravity=0", "gravity must be a positive number"),
+            ("gravity=inf", "gravity must be a positive number"),
+            (
+                "drag_coefficient=-0.5",
+                "drag_coefficient must not be negative",
+            ),
+            ("air_density=NaN", "air_density must be a positive number"),
+            (
+                "stopping_distance=0",
+                "stopping_distance must be a positive number",
+            ),
+            (
+                "cross_section=-1",
+                "cross_section must be a positive number",
+            ),
+        ] {
+            let (status, body) = fetch(&format!("/8/physics/25?{}", query)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+                json!({ "error": error })
+            );
+        }
+
+        let (status, body) = fetch("/8/physics/25?drag_coefficient=1.05").await;
+        assert_eq!(status, StatusCode::OK);
+        let fall: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(fall["velocity"].as_f64() < fall["terminal_velocity"].as_f64());
+    }
 }
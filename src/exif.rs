@@ -0,0 +1,442 @@
+//! Reads the common EXIF fields out of jpeg, png, webp and tiff files.
+//!
+//! Only the tags day11 reports are decoded. Anything malformed is skipped
+//! rather than treated as an error, since metadata is often half broken.
+
+use image::ImageFormat;
+use serde::Serialize;
+
+/// The raw tiff structure holding the EXIF data, if the container has one.
+pub fn find(format: ImageFormat, bytes: &[u8]) -> Option<&[u8]> {
+    match format {
+        ImageFormat::Jpeg => find_in_jpeg(bytes),
+        ImageFormat::Png => find_in_png(bytes),
+        ImageFormat::WebP => find_in_webp(bytes),
+        ImageFormat::Tiff => Some(bytes),
+        _ => None,
+    }
+}
+
+fn find_in_jpeg(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 2;
+
+    while bytes.get(pos)? == &0xff {
+        let marker = *bytes.get(pos + 1)?;
+        // start of scan, no more metadata segments follow
+        if marker == 0xda {
+            return None;
+        }
+
+        let len = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]) as usize;
+        let segment = bytes.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xe1 {
+            if let Some(tiff) = segment.strip_prefix(b"Exif\0\0") {
+                return Some(tiff);
+            }
+        }
+        pos += 2 + len;
+    }
+
+    None
+}
+
+fn find_in_png(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 8;
+
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let len = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+        if &header[4..] == b"eXIf" {
+            return bytes.get(pos + 8..pos + 8 + len);
+        }
+        pos += 12 + len;
+    }
+
+    None
+}
+
+fn find_in_webp(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 12;
+
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let len = u32::from_le_bytes(header[4..].try_into().ok()?) as usize;
+        if &header[..4] == b"EXIF" {
+            let chunk = bytes.get(pos + 8..pos + 8 + len)?;
+            // some writers keep the jpeg style prefix
+            return Some(chunk.strip_prefix(b"Exif\0\0").unwrap_or(chunk));
+        }
+        pos += 8 + len + len % 2;
+    }
+
+    None
+}
+
+#[derive(Default, Serialize)]
+pub struct Camera {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub software: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct Timestamps {
+    pub modified: Option<String>,
+    pub original: Option<String>,
+    pub digitized: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct Exposure {
+    /// Seconds, as the usual fraction like `1/125`.
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    /// Millimetres.
+    pub focal_length: Option<f64>,
+}
+
+#[derive(Default, Serialize)]
+pub struct Gps {
+    /// Decimal degrees, negative south of the equator.
+    pub latitude: Option<f64>,
+    /// Decimal degrees, negative west of Greenwich.
+    pub longitude: Option<f64>,
+    /// Metres, negative below sea level.
+    pub altitude: Option<f64>,
+    pub timestamp: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct Exif {
+    pub camera: Camera,
+    /// 1 to 8, as defined by the tiff orientation tag.
+    pub orientation: Option<u16>,
+    pub timestamps: Timestamps,
+    pub exposure: Exposure,
+    pub gps: Option<Gps>,
+}
+
+const TAG_MAKE: u16 = 0x010f;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_SOFTWARE: u16 = 0x0131;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+
+const TAG_EXPOSURE_TIME: u16 = 0x829a;
+const TAG_F_NUMBER: u16 = 0x829d;
+const TAG_ISO: u16 = 0x8827;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_DATE_TIME_DIGITIZED: u16 = 0x9004;
+const TAG_FOCAL_LENGTH: u16 = 0x920a;
+const TAG_LENS_MODEL: u16 = 0xa434;
+
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+const TAG_GPS_ALTITUDE_REF: u16 = 0x0005;
+const TAG_GPS_ALTITUDE: u16 = 0x0006;
+const TAG_GPS_TIME: u16 = 0x0007;
+const TAG_GPS_DATE: u16 = 0x001d;
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: usize,
+    // absolute position of the value, inline or not
+    value: usize,
+}
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+
+        Some(Tiff { data, big_endian })
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes = self.data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn ifd(&self, offset: usize) -> Vec<Entry> {
+        let count = self.u16_at(offset).unwrap_or(0) as usize;
+
+        (0..count)
+            .map_while(|i| {
+                let pos = offset + 2 + i * 12;
+                let kind = self.u16_at(pos + 2)?;
+                let count = self.u32_at(pos + 4)? as usize;
+                let size: usize = match kind {
+                    1 | 2 | 6 | 7 => 1,
+                    3 | 8 => 2,
+                    4 | 9 | 11 => 4,
+                    5 | 10 | 12 => 8,
+                    _ => 0,
+                };
+                let value = match size.checked_mul(count)? {
+                    0..=4 => pos + 8,
+                    _ => self.u32_at(pos + 8)? as usize,
+                };
+
+                Some(Entry {
+                    tag: self.u16_at(pos)?,
+                    kind,
+                    count,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    fn ascii(&self, entries: &[Entry], tag: u16) -> Option<String> {
+        let entry = entries.iter().find(|e| e.tag == tag && e.kind == 2)?;
+        let raw = self.data.get(entry.value..entry.value + entry.count)?;
+        let text = String::from_utf8_lossy(raw);
+        let text = text.trim_end_matches('\0').trim();
+
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    fn unsigned(&self, entries: &[Entry], tag: u16) -> Option<u32> {
+        let entry = entries.iter().find(|e| e.tag == tag)?;
+        match entry.kind {
+            1 | 7 => self.data.get(entry.value).map(|b| *b as u32),
+            3 => self.u16_at(entry.value).map(u32::from),
+            4 => self.u32_at(entry.value),
+            _ => None,
+        }
+    }
+
+    fn rationals(&self, entries: &[Entry], tag: u16) -> Option<Vec<(u32, u32)>> {
+        let entry = entries
+            .iter()
+            .find(|e| e.tag == tag && matches!(e.kind, 5 | 10))?;
+
+        (0..entry.count)
+            .map(|i| {
+                let pos = entry.value + i * 8;
+                Some((self.u32_at(pos)?, self.u32_at(pos + 4)?))
+            })
+            .collect()
+    }
+
+    fn rational(&self, entries: &[Entry], tag: u16) -> Option<f64> {
+        let (numerator, denominator) = *self.rationals(entries, tag)?.first()?;
+        (denominator != 0).then(|| numerator as f64 / denominator as f64)
+    }
+
+    fn coordinate(&self, entries: &[Entry], tag: u16, reference: u16) -> Option<f64> {
+        let parts = self.rationals(entries, tag)?;
+        let degrees = parts
+            .iter()
+            .zip([1.0, 60.0, 3600.0])
+            .map(|((numerator, denominator), scale)| match denominator {
+                0 => 0.0,
+                _ => *numerator as f64 / *denominator as f64 / scale,
+            })
+            .sum::<f64>();
+
+        match self.ascii(entries, reference).as_deref() {
+            Some("S") | Some("W") => Some(-degrees),
+            _ => Some(degrees),
+        }
+    }
+}
+
+/// Decodes the fields we report from a tiff structure found by [`find`].
+pub fn parse(data: &[u8]) -> Option<Exif> {
+    let tiff = Tiff::new(data)?;
+    let ifd0 = tiff.ifd(tiff.u32_at(4)? as usize);
+
+    let exif_ifd = tiff
+        .unsigned(&ifd0, TAG_EXIF_IFD)
+        .map(|offset| tiff.ifd(offset as usize))
+        .unwrap_or_default();
+
+    let exposure_time = tiff
+        .rationals(&exif_ifd, TAG_EXPOSURE_TIME)
+        .and_then(|parts| parts.first().copied())
+        .filter(|(_, denominator)| *denominator != 0)
+        .map(|(numerator, denominator)| match numerator {
+            1 => format!("1/{}", denominator),
+            _ => (numerator as f64 / denominator as f64).to_string(),
+        });
+
+    let gps = tiff.unsigned(&ifd0, TAG_GPS_IFD).map(|offset| {
+        let gps_ifd = tiff.ifd(offset as usize);
+
+        let altitude = tiff.rational(&gps_ifd, TAG_GPS_ALTITUDE).map(|altitude| {
+            match tiff.unsigned(&gps_ifd, TAG_GPS_ALTITUDE_REF) {
+                Some(1) => -altitude,
+                _ => altitude,
+            }
+        });
+        let time = tiff.rationals(&gps_ifd, TAG_GPS_TIME).map(|parts| {
+            let [h, m, s] = [0, 1, 2].map(|i| {
+                parts
+                    .get(i)
+                    .filter(|(_, d)| *d != 0)
+                    .map_or(0.0, |(n, d)| *n as f64 / *d as f64)
+            });
+            format!("{:02}:{:02}:{:02}", h as u32, m as u32, s as u32)
+        });
+        let timestamp = match (tiff.ascii(&gps_ifd, TAG_GPS_DATE), time) {
+            (Some(date), Some(time)) => Some(format!("{} {}", date, time)),
+            (date, time) => date.or(time),
+        };
+
+        Gps {
+            latitude: tiff.coordinate(&gps_ifd, TAG_GPS_LATITUDE, TAG_GPS_LATITUDE_REF),
+            longitude: tiff.coordinate(&gps_ifd, TAG_GPS_LONGITUDE, TAG_GPS_LONGITUDE_REF),
+            altitude,
+            timestamp,
+        }
+    });
+
+    Some(Exif {
+        camera: Camera {
+            make: tiff.ascii(&ifd0, TAG_MAKE),
+            model: tiff.ascii(&ifd0, TAG_MODEL),
+            lens: tiff.ascii(&exif_ifd, TAG_LENS_MODEL),
+            software: tiff.ascii(&ifd0, TAG_SOFTWARE),
+        },
+        orientation: tiff
+            .unsigned(&ifd0, TAG_ORIENTATION)
+            .map(|orientation| orientation as u16),
+        timestamps: Timestamps {
+            modified: tiff.ascii(&ifd0, TAG_DATE_TIME),
+            original: tiff.ascii(&exif_ifd, TAG_DATE_TIME_ORIGINAL),
+            digitized: tiff.ascii(&exif_ifd, TAG_DATE_TIME_DIGITIZED),
+        },
+        exposure: Exposure {
+            exposure_time,
+            f_number: tiff.rational(&exif_ifd, TAG_F_NUMBER),
+            iso: tiff.unsigned(&exif_ifd, TAG_ISO),
+            focal_length: tiff.rational(&exif_ifd, TAG_FOCAL_LENGTH),
+        },
+        gps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little endian tiff with camera, exposure and gps fields at fixed offsets.
+    fn sample_tiff() -> Vec<u8> {
+        let mut data = vec![0u8; 240];
+        let mut put =
+            |pos: usize, bytes: &[u8]| data[pos..pos + bytes.len()].copy_from_slice(bytes);
+        let entry = |tag: u16, kind: u16, count: u32, value: u32| {
+            [
+                &tag.to_le_bytes()[..],
+                &kind.to_le_bytes(),
+                &count.to_le_bytes(),
+                &value.to_le_bytes(),
+            ]
+            .concat()
+        };
+
+        put(0, b"II*\0");
+        put(4, &8u32.to_le_bytes());
+
+        put(8, &4u16.to_le_bytes());
+        put(10, &entry(TAG_MAKE, 2, 6, 100));
+        put(22, &entry(TAG_ORIENTATION, 3, 1, 6));
+        put(34, &entry(TAG_EXIF_IFD, 4, 1, 120));
+        put(46, &entry(TAG_GPS_IFD, 4, 1, 160));
+        put(100, b"Canon\0");
+
+        put(120, &2u16.to_le_bytes());
+        put(122, &entry(TAG_EXPOSURE_TIME, 5, 1, 200));
+        put(134, &entry(TAG_ISO, 3, 1, 400));
+        put(200, &[1u32.to_le_bytes(), 125u32.to_le_bytes()].concat());
+
+        put(160, &2u16.to_le_bytes());
+        put(162, &entry(TAG_GPS_LATITUDE_REF, 2, 2, u32::from(b'S')));
+        put(174, &entry(TAG_GPS_LATITUDE, 5, 3, 210));
+        for (i, (numerator, denominator)) in
+            [(33u32, 1u32), (30, 1), (0, 1)].into_iter().enumerate()
+        {
+            put(210 + i * 8, &numerator.to_le_bytes());
+            put(214 + i * 8, &denominator.to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn reads_fields_from_every_ifd() {
+        let exif = parse(&sample_tiff()).unwrap();
+
+        assert_eq!(exif.camera.make.as_deref(), Some("Canon"));
+        assert_eq!(exif.orientation, Some(6));
+        assert_eq!(exif.exposure.exposure_time.as_deref(), Some("1/125"));
+        assert_eq!(exif.exposure.iso, Some(400));
+        assert_eq!(exif.gps.unwrap().latitude, Some(-33.5));
+    }
+
+    #[test]
+    fn reads_big_endian_values() {
+        let mut data = b"MM\0*".to_vec();
+        data.extend_from_slice(&8u32.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&TAG_ORIENTATION.to_be_bytes());
+        data.extend_from_slice(&3u16.to_be_bytes());
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&[0, 8, 0, 0]);
+
+        assert_eq!(parse(&data).unwrap().orientation, Some(8));
+    }
+
+    #[test]
+    fn skips_what_is_cut_off() {
+        let data = sample_tiff();
+
+        // every prefix parses or is refused without panicking
+        for len in 0..data.len() {
+            let _ = parse(&data[..len]);
+        }
+
+        let exif = parse(&data[..150]).unwrap();
+        assert_eq!(exif.camera.make.as_deref(), Some("Canon"));
+        assert_eq!(exif.gps.unwrap().latitude, None);
+        assert!(parse(b"not a tiff").is_none());
+    }
+
+    #[test]
+    fn finds_the_jpeg_app1_segment() {
+        let tiff = sample_tiff();
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0, 0xff, 0xe1];
+        jpeg.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&[0xff, 0xda]);
+
+        assert_eq!(find(ImageFormat::Jpeg, &jpeg), Some(&tiff[..]));
+        assert_eq!(find(ImageFormat::Jpeg, &jpeg[..20]), None);
+    }
+}
@@ -0,0 +1,244 @@
+//! Text forms of 128 bit identifiers.
+//!
+//! ULIDs and UUIDs are both 128 bit values, so any id can be written in any
+//! of the forms below and read back without loss.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use derive_more::{Display, Error};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+use uuid::Uuid;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Debug, Display, Error)]
+pub enum IdError {
+    #[display(fmt = "not a valid {}", _0)]
+    Invalid(#[error(not(source))] IdForm),
+
+    #[display(fmt = "value does not fit in 128 bits")]
+    Overflow,
+
+    #[display(fmt = "could not tell which form the id is in")]
+    Unrecognised,
+
+    #[display(fmt = "expected a string or an integer")]
+    NotText,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdForm {
+    /// 26 characters of Crockford base32.
+    #[display(fmt = "ulid")]
+    Ulid,
+    /// Hyphenated hex, or any other form the uuid crate parses.
+    #[display(fmt = "uuid")]
+    Uuid,
+    /// Crockford base32 without leading zeros.
+    #[display(fmt = "base32")]
+    Base32,
+    /// The 16 bytes big endian, unpadded.
+    #[display(fmt = "base64url")]
+    Base64url,
+    /// Decimal.
+    #[display(fmt = "integer")]
+    Integer,
+}
+
+impl IdForm {
+    /// Guesses the form from the length of the input. Base32 is never
+    /// guessed, as short base32 is indistinguishable from an integer.
+    fn detect(input: &str) -> Option<IdForm> {
+        match input.len() {
+            26 => Some(IdForm::Ulid),
+            32 | 36 | 38 | 45 => Some(IdForm::Uuid),
+            22 if !input.bytes().all(|b| b.is_ascii_digit()) => Some(IdForm::Base64url),
+            _ if !input.is_empty() && input.bytes().all(|b| b.is_ascii_digit()) => {
+                Some(IdForm::Integer)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads an id, in the given form or else the one guessed from its shape.
+/// Returns the value and the form it was read as.
+pub fn parse(input: &str, form: Option<IdForm>) -> Result<(u128, IdForm), IdError> {
+    let input = input.trim();
+    let form = form
+        .or_else(|| IdForm::detect(input))
+        .ok_or(IdError::Unrecognised)?;
+    let invalid = IdError::Invalid(form);
+
+    let value = match form {
+        // the ulid crate wraps anything past 7ZZZZZZZZZZZZZZZZZZZZZZZZZ
+        IdForm::Ulid if input.starts_with(|c: char| c.is_ascii_alphanumeric() && c > '7') => {
+            return Err(IdError::Overflow)
+        }
+        IdForm::Ulid => Ulid::from_string(input).map_err(|_| invalid)?.0,
+        IdForm::Uuid => Uuid::parse_str(input).map_err(|_| invalid)?.as_u128(),
+        IdForm::Base32 => decode_base32(input)?,
+        IdForm::Base64url => {
+            let bytes = URL_SAFE_NO_PAD
+                .decode(input.trim_end_matches('='))
+                .map_err(|_| invalid)?;
+            let bytes: [u8; 16] = bytes.try_into().map_err(|_| IdError::Invalid(form))?;
+            u128::from_be_bytes(bytes)
+        }
+        IdForm::Integer => match input.parse::<u128>() {
+            Ok(value) => value,
+            Err(_) if input.bytes().all(|b| b.is_ascii_digit()) && !input.is_empty() => {
+                return Err(IdError::Overflow)
+            }
+            Err(_) => return Err(invalid),
+        },
+    };
+
+    Ok((value, form))
+}
+
+pub fn format(value: u128, form: IdForm) -> String {
+    match form {
+        IdForm::Ulid => Ulid(value).to_string(),
+        IdForm::Uuid => Uuid::from_u128(value).hyphenated().to_string(),
+        IdForm::Base32 => encode_base32(value),
+        IdForm::Base64url => URL_SAFE_NO_PAD.encode(value.to_be_bytes()),
+        IdForm::Integer => value.to_string(),
+    }
+}
+
+fn encode_base32(mut value: u128) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(CROCKFORD[(value & 31) as usize]);
+        value >>= 5;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+
+    String::from_utf8(digits).unwrap()
+}
+
+/// Decodes Crockford base32: case-insensitive, hyphens ignored, and I, L
+/// and O read as the digits they look like.
+fn decode_base32(input: &str) -> Result<u128, IdError> {
+    let mut value: u128 = 0;
+    let mut digits = 0;
+
+    for c in input.chars().filter(|&c| c != '-') {
+        let digit = match c.to_ascii_uppercase() {
+            'O' => 0,
+            'I' | 'L' => 1,
+            c => CROCKFORD
+                .iter()
+                .position(|&d| d as char == c)
+                .ok_or(IdError::Invalid(IdForm::Base32))? as u128,
+        };
+        value = value
+            .checked_mul(32)
+            .map(|value| value | digit)
+            .ok_or(IdError::Overflow)?;
+        digits += 1;
+    }
+
+    if digits == 0 {
+        return Err(IdError::Invalid(IdForm::Base32));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ULID: &str = "01BX5ZZKBKACTAV9WEVGEMMVRZ";
+    const VALUE: u128 = 1824037644831285921095405231938367263;
+
+    const FORMS: [IdForm; 5] = [
+        IdForm::Ulid,
+        IdForm::Uuid,
+        IdForm::Base32,
+        IdForm::Base64url,
+        IdForm::Integer,
+    ];
+
+    #[test]
+    fn writes_every_form() {
+        assert_eq!(format(VALUE, IdForm::Ulid), ULID);
+        assert_eq!(
+            format(VALUE, IdForm::Uuid),
+            "015f4bff-cd73-5334-ada7-8edc1d4a6f1f"
+        );
+        assert_eq!(format(VALUE, IdForm::Base32), "1BX5ZZKBKACTAV9WEVGEMMVRZ");
+        assert_eq!(format(VALUE, IdForm::Base64url), "AV9L_81zUzStp47cHUpvHw");
+        assert_eq!(format(VALUE, IdForm::Integer), VALUE.to_string());
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        for value in [0, 1, 31, 32, VALUE, u128::MAX >> 1, u128::MAX] {
+            for form in FORMS {
+                let text = format(value, form);
+                assert_eq!(parse(&text, Some(form)).unwrap(), (value, form), "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn guesses_the_form_from_the_shape() {
+        for form in [
+            IdForm::Ulid,
+            IdForm::Uuid,
+            IdForm::Base64url,
+            IdForm::Integer,
+        ] {
+            assert_eq!(parse(&format(VALUE, form), None).unwrap(), (VALUE, form));
+        }
+        assert_eq!(
+            parse("015F4BFFCD735334ADA78EDC1D4A6F1F", None).unwrap(),
+            (VALUE, IdForm::Uuid)
+        );
+        assert!(matches!(parse("", None), Err(IdError::Unrecognised)));
+        assert!(matches!(
+            parse("not an id", None),
+            Err(IdError::Unrecognised)
+        ));
+    }
+
+    #[test]
+    fn reads_base32_leniently() {
+        assert_eq!(decode_base32("0o-Il").unwrap(), 1 | 1 << 5);
+        assert_eq!(decode_base32("zz").unwrap(), 1023);
+        assert!(matches!(decode_base32("U"), Err(IdError::Invalid(_))));
+        assert!(matches!(decode_base32("--"), Err(IdError::Invalid(_))));
+    }
+
+    #[test]
+    fn refuses_values_past_128_bits() {
+        // the ulid crate would wrap these around instead
+        assert!(matches!(
+            parse("80000000000000000000000000", Some(IdForm::Ulid)),
+            Err(IdError::Overflow)
+        ));
+        assert!(matches!(
+            parse("8zzzzzzzzzzzzzzzzzzzzzzzzz", None),
+            Err(IdError::Overflow)
+        ));
+        assert!(matches!(
+            parse(&format!("{}0", u128::MAX), Some(IdForm::Integer)),
+            Err(IdError::Overflow)
+        ));
+        assert!(matches!(
+            parse("80000000000000000000000000", Some(IdForm::Base32)),
+            Err(IdError::Overflow)
+        ));
+        assert!(matches!(
+            parse("AV9L_81zUzStp47cHUpv", Some(IdForm::Base64url)),
+            Err(IdError::Invalid(IdForm::Base64url))
+        ));
+    }
+}
@@ -1,4 +1,7 @@
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::{
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc},
+};
 
 use actix::Actor;
 use actix_web::{
@@ -6,12 +9,20 @@ use actix_web::{
     web::{self, ServiceConfig},
     HttpResponse, Responder,
 };
+use assets::AssetStore;
+use clock::Clock;
+use day11::{HashIndex, ImageLimits, TransformCache};
 use day19::ChatServer;
+use day8::{LocalProvider, PokeApiProvider, PokemonProvider};
+use namespace::Namespaces;
 use shuttle_actix_web::ShuttleActixWeb;
 use shuttle_persist::PersistInstance;
 use shuttle_secrets::SecretStore;
 use sqlx::PgPool;
+use storage::{KeyValueStore, LocalStore, MemoryStore, PersistStore, PostgresStore};
 
+mod assets;
+mod clock;
 mod day1;
 mod day11;
 mod day12;
@@ -27,6 +38,11 @@ mod day4;
 mod day6;
 mod day7;
 mod day8;
+mod exif;
+mod ids;
+mod namespace;
+mod storage;
+mod tz;
 
 #[get("/")]
 async fn base() -> impl Responder {
@@ -40,12 +56,58 @@ async fn fake_error() -> impl Responder {
 
 struct AppSecrets {
     position_stack_api_key: String,
+    recipe_cookie_key: Vec<u8>,
+    // only true when the key was configured, otherwise plain cookies still work
+    signed_recipes_only: bool,
 }
 
 struct AppState {
-    persist: PersistInstance,
+    storage: Arc<dyn KeyValueStore>,
     pool: PgPool,
     secrets: AppSecrets,
+    pokemon: Arc<dyn PokemonProvider>,
+    assets: Arc<AssetStore>,
+    transforms: Arc<TransformCache>,
+    image_limits: ImageLimits,
+    hashes: Arc<HashIndex>,
+    clock: Arc<Clock>,
+    namespaces: Arc<Namespaces>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// In-memory backends and a pool that never connects, for handler tests
+    /// that stay away from the database.
+    fn for_tests() -> Self {
+        AppState {
+            storage: Arc::new(MemoryStore::default()),
+            pool: sqlx::postgres::PgPoolOptions::new()
+                .connect_lazy("postgres://localhost/unused")
+                .unwrap(),
+            secrets: AppSecrets {
+                position_stack_api_key: String::new(),
+                recipe_cookie_key: b"test key".to_vec(),
+                signed_recipes_only: false,
+            },
+            pokemon: Arc::new(LocalProvider::bundled()),
+            assets: Arc::new(
+                AssetStore::new(tempfile::tempdir().unwrap().into_path(), None).unwrap(),
+            ),
+            transforms: Arc::new(TransformCache::default()),
+            image_limits: ImageLimits::default(),
+            hashes: Arc::new(HashIndex::default()),
+            clock: Arc::new(Clock::new(true)),
+            namespaces: Arc::new(Namespaces::new(namespace::DEFAULT_LIMIT, &[], None)),
+        }
+    }
+}
+
+fn parse_secret<T: FromStr>(secret_store: &SecretStore, name: &str) -> Option<T> {
+    secret_store.get(name).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not a valid number", name))
+    })
 }
 
 #[shuttle_runtime::main]
@@ -54,23 +116,147 @@ async fn main(
     #[shuttle_shared_db::Postgres] pool: PgPool,
     #[shuttle_secrets::Secrets] secret_store: SecretStore,
 ) -> ShuttleActixWeb<impl FnOnce(&mut ServiceConfig) + Send + Clone + 'static> {
+    // migrations/ is embedded at build time, applied ones are recorded in
+    // _sqlx_migrations and skipped
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .expect("run database migrations");
+
+    // STORAGE_BACKEND picks where day12 packets and other small state live,
+    // built once here so every worker shares the same store
+    let storage: Arc<dyn KeyValueStore> = match secret_store.get("STORAGE_BACKEND").as_deref() {
+        None | Some("persist") => Arc::new(PersistStore::new(persist)),
+        Some("local") => Arc::new(
+            LocalStore::new(
+                secret_store
+                    .get("STORAGE_DIR")
+                    .unwrap_or_else(|| "storage".to_string()),
+            )
+            .expect("open storage directory"),
+        ),
+        Some("memory") => Arc::new(MemoryStore::default()),
+        Some("postgres") => Arc::new(PostgresStore::new(pool.clone())),
+        Some(other) => panic!("unknown STORAGE_BACKEND {}", other),
+    };
+
+    // POKEMON_PROVIDER=local serves day8 from POKEMON_DATASET, or the bundled
+    // fixture, read once here rather than by every worker
+    let pokemon: Arc<dyn PokemonProvider> = match secret_store.get("POKEMON_PROVIDER").as_deref() {
+        None | Some("pokeapi") => Arc::new(PokeApiProvider::new()),
+        Some("local") => match secret_store.get("POKEMON_DATASET") {
+            Some(path) => Arc::new(LocalProvider::from_file(path).expect("load pokemon dataset")),
+            None => Arc::new(LocalProvider::bundled()),
+        },
+        Some(other) => panic!("unknown POKEMON_PROVIDER {}", other),
+    };
+
+    // DEBUG_CLOCK=true lets requests freeze or offset time, for tests and demos
+    let clock = Arc::new(Clock::new(
+        secret_store.get("DEBUG_CLOCK").as_deref() == Some("true"),
+    ));
+
+    day12::prefix_legacy_packets(storage.as_ref())
+        .await
+        .expect("move stored packets under their key prefix");
+    tokio::spawn(day12::sweep_expired(storage.clone()));
+
+    // without a configured key, signed recipes only survive until the next
+    // restart, but are still shared by every worker until then
+    let configured_cookie_key = secret_store.get("RECIPE_COOKIE_KEY");
+    let signed_recipes_only = configured_cookie_key.is_some();
+    let recipe_cookie_key = configured_cookie_key
+        .map(String::into_bytes)
+        .unwrap_or_else(|| rand::random::<[u8; 32]>().to_vec());
+
+    // day11's asset store and caches are shared too, so the asset root is
+    // opened once, each etag is hashed once, memory stays bounded and /11/hash
+    // sees every indexed asset whichever worker answers. ASSETS_TOKEN enables
+    // uploads and deletes, leaving it unset keeps assets read only
+    let assets = Arc::new(
+        AssetStore::new(
+            secret_store
+                .get("ASSETS_ROOT")
+                .unwrap_or_else(|| "assets".to_string()),
+            secret_store.get("ASSETS_TOKEN"),
+        )
+        .expect("open asset root"),
+    );
+    let transforms = Arc::new(TransformCache::default());
+    let hashes = Arc::new(HashIndex::default());
+
+    // one registry for every worker, so a namespace is only prepared once.
+    // NAMESPACES_TOKEN enables listing and dropping namespaces
+    let namespaces = Arc::new(Namespaces::new(
+        parse_secret(&secret_store, "NAMESPACE_LIMIT").unwrap_or(namespace::DEFAULT_LIMIT),
+        &[day13::DEFAULT_NAMESPACE, day18::DEFAULT_NAMESPACE],
+        secret_store.get("NAMESPACES_TOKEN"),
+    ));
+    namespaces
+        .prepare_defaults(&pool)
+        .await
+        .expect("create the default namespaces");
+
     let config = move |cfg: &mut ServiceConfig| {
         cfg.service(base);
         cfg.service(fake_error);
+        cfg.service(clock::clock_get);
+        cfg.service(clock::clock_put);
+        cfg.service(clock::clock_delete);
+        cfg.service(namespace::namespaces_list);
+        cfg.service(namespace::namespaces_delete);
         cfg.service(day1::day_1);
         cfg.service(day4::day_4_strength);
         cfg.service(day4::day_4_contest);
         cfg.service(day6::day_6);
+        cfg.service(day6::day_6_count);
         cfg.service(day7::day_7_decode);
         cfg.service(day7::day_7_bake);
+        cfg.service(day7::day_7_sign);
+        cfg.service(day7::day_7_optimise);
+        cfg.service(day7::day_7_create_recipe);
+        cfg.service(day7::day_7_list_recipes);
+        cfg.service(day7::day_7_get_recipe);
+        cfg.service(day7::day_7_recipe_versions);
+        cfg.service(day7::day_7_update_recipe);
+        cfg.service(day7::day_7_delete_recipe);
+        cfg.service(day7::day_7_create_pantry);
+        cfg.service(day7::day_7_list_pantries);
+        cfg.service(day7::day_7_get_pantry);
+        cfg.service(day7::day_7_update_pantry);
+        cfg.service(day7::day_7_delete_pantry);
+        cfg.service(day7::day_7_bake_pantry);
         cfg.service(day8::day_8_weight);
         cfg.service(day8::day_8_drop);
+        cfg.service(day8::day_8_pokemon);
+        cfg.service(day8::day_8_physics);
+        cfg.service(day8::day_8_team);
+        cfg.service(day11::day_11_list_assets);
         cfg.service(day11::day_11_image);
+        cfg.service(day11::day_11_upload_asset);
+        cfg.service(day11::day_11_delete_asset);
         cfg.service(day11::day_11_red_pixels);
+        cfg.service(day11::day_11_red_pixels_mask);
+        cfg.service(day11::day_11_analyze);
+        cfg.service(day11::day_11_transform);
+        cfg.service(day11::day_11_compare);
+        cfg.service(day11::day_11_hash);
+        cfg.service(day11::day_11_metadata);
         cfg.service(day12::day_12_save);
         cfg.service(day12::day_12_load);
+        cfg.service(day12::day_12_list_packets);
+        cfg.service(day12::day_12_packet);
+        cfg.service(day12::day_12_delete_packets);
+        cfg.service(day12::day_12_delete_packet);
+        cfg.service(day12::day_12_packet_expiry);
+        cfg.service(day12::day_12_export);
+        cfg.service(day12::day_12_import);
         cfg.service(day12::day_12_ulids);
         cfg.service(day12::day_12_lsb);
+        cfg.service(day12::day_12_analyze);
+        cfg.service(day12::day_12_generate_ids);
+        cfg.service(day12::day_12_inspect_ids);
+        cfg.service(day12::day_12_convert_ids);
         cfg.service(day13::day_13_select);
         cfg.service(day13::day_13_reset);
         cfg.service(day13::day_13_create_orders);
@@ -100,12 +286,36 @@ async fn main(
 
         let secrets = AppSecrets {
             position_stack_api_key: secret_store.get("POSITION_STACK_API_KEY").unwrap(),
+            recipe_cookie_key: recipe_cookie_key.clone(),
+            signed_recipes_only,
+        };
+
+        // IMAGE_MAX_* override the bounds day11 checks before decoding an upload
+        let defaults = ImageLimits::default();
+        let image_limits = ImageLimits {
+            max_width: parse_secret(&secret_store, "IMAGE_MAX_WIDTH").unwrap_or(defaults.max_width),
+            max_height: parse_secret(&secret_store, "IMAGE_MAX_HEIGHT")
+                .unwrap_or(defaults.max_height),
+            max_pixels: parse_secret(&secret_store, "IMAGE_MAX_PIXELS")
+                .unwrap_or(defaults.max_pixels),
+            max_frames: parse_secret(&secret_store, "IMAGE_MAX_FRAMES")
+                .unwrap_or(defaults.max_frames),
+            max_upload_bytes: parse_secret(&secret_store, "IMAGE_MAX_UPLOAD_BYTES")
+                .unwrap_or(defaults.max_upload_bytes),
         };
+        cfg.app_data(image_limits.multipart_config());
 
         let app_data = web::Data::new(AppState {
-            persist,
+            storage: storage.clone(),
             pool,
             secrets,
+            pokemon: pokemon.clone(),
+            assets: assets.clone(),
+            transforms: transforms.clone(),
+            image_limits,
+            hashes: hashes.clone(),
+            clock: clock.clone(),
+            namespaces: namespaces.clone(),
         });
         cfg.app_data(app_data.clone());
 
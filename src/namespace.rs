@@ -0,0 +1,349 @@
+//! Separate copies of the day13 and day18 order tables.
+//!
+//! Each namespace is a Postgres schema holding its own `orders` and
+//! `regions`, created on first use from the migrated tables in `public`,
+//! which are otherwise left empty. Queries run in a transaction whose search
+//! path points at the namespace, so their SQL names the tables unqualified.
+//!
+//! The `namespaces` table records the template version each schema was built
+//! from. A namespace behind [`SCHEMA_VERSION`] is rebuilt from the templates
+//! on its first use, keeping the rows of every column that is left. At most
+//! `NAMESPACE_LIMIT` namespaces exist at once besides the defaults, which are
+//! created at boot, and `DELETE /namespaces/{name}` drops one. Listing and
+//! dropping need the `NAMESPACES_TOKEN` bearer token.
+
+use std::{collections::HashSet, sync::RwLock};
+
+use actix_web::{
+    delete, error, get,
+    http::{header::ContentType, StatusCode},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use derive_more::{Display, Error};
+use serde_json::json;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{assets::bearer_matches, AppState};
+
+pub const NAMESPACE_HEADER: &str = "X-Namespace";
+
+// tables copied into every namespace, in the order they are created
+const TABLES: [&str; 2] = ["regions", "orders"];
+
+// namespaces allowed at once when NAMESPACE_LIMIT is not set
+pub const DEFAULT_LIMIT: i64 = 100;
+
+/// Version of the `orders` and `regions` templates. Bump it in the same
+/// change as a migration that alters either table, and only then, since
+/// every existing namespace is rebuilt once it is behind.
+pub const SCHEMA_VERSION: i64 = 1;
+
+#[derive(Debug, Display, Error)]
+pub enum NamespaceError {
+    #[display(fmt = "namespace names must be 1 to 32 lowercase letters, digits or underscores")]
+    Invalid,
+
+    #[display(fmt = "no namespace named {}", _0)]
+    NotFound(#[error(not(source))] String),
+
+    #[display(fmt = "there are already {} namespaces, drop one first", _0)]
+    LimitReached(#[error(not(source))] i64),
+
+    #[display(fmt = "missing or wrong namespace token")]
+    Unauthorized,
+
+    #[display(fmt = "namespace management is disabled")]
+    ManagementDisabled,
+
+    #[display(fmt = "database error")]
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for NamespaceError {
+    fn from(err: sqlx::Error) -> Self {
+        NamespaceError::Database(err)
+    }
+}
+
+impl error::ResponseError for NamespaceError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .json(json!({
+               "error": self.to_string()
+            }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            NamespaceError::Invalid => StatusCode::BAD_REQUEST,
+            NamespaceError::NotFound(_) => StatusCode::NOT_FOUND,
+            NamespaceError::LimitReached(_) => StatusCode::CONFLICT,
+            NamespaceError::Unauthorized => StatusCode::UNAUTHORIZED,
+            NamespaceError::ManagementDisabled => StatusCode::FORBIDDEN,
+            NamespaceError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Built once and shared by every worker.
+pub struct Namespaces {
+    limit: i64,
+    // used when a request names no namespace, never counted against the limit
+    defaults: Vec<String>,
+    // for listing and dropping, which are disabled without one
+    token: Option<String>,
+    // namespaces known to be up to date, so they are not checked again
+    ready: RwLock<HashSet<String>>,
+}
+
+impl Namespaces {
+    pub fn new(limit: i64, defaults: &[&str], token: Option<String>) -> Self {
+        Namespaces {
+            limit,
+            defaults: defaults.iter().map(|name| name.to_string()).collect(),
+            token,
+            ready: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Checks the `Authorization: Bearer <token>` header of a management call.
+    fn authorize(&self, req: &HttpRequest) -> Result<(), NamespaceError> {
+        let token = self
+            .token
+            .as_ref()
+            .ok_or(NamespaceError::ManagementDisabled)?;
+        if !bearer_matches(req, token) {
+            return Err(NamespaceError::Unauthorized);
+        }
+
+        Ok(())
+    }
+
+    /// Creates or upgrades the default namespaces, so they exist however many
+    /// others clients have made. Call it after the migrations have run.
+    pub async fn prepare_defaults(&self, pool: &PgPool) -> Result<(), NamespaceError> {
+        for name in &self.defaults {
+            self.prepare(pool, name, &schema_for(name)?).await?;
+            self.ready.write().unwrap().insert(name.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Begins a transaction in the caller's namespace: the one named by the
+    /// `X-Namespace` header, or else `default`.
+    pub async fn begin(
+        &self,
+        pool: &PgPool,
+        req: &HttpRequest,
+        default: &str,
+    ) -> Result<Transaction<'static, Postgres>, NamespaceError> {
+        let name = match req.headers().get(NAMESPACE_HEADER) {
+            Some(value) => value.to_str().map_err(|_| NamespaceError::Invalid)?,
+            None => default,
+        };
+        let schema = schema_for(name)?;
+        if !self.ready.read().unwrap().contains(name) {
+            self.prepare(pool, name, &schema).await?;
+            self.ready.write().unwrap().insert(name.to_string());
+        }
+
+        let mut tx = pool.begin().await?;
+        sqlx::query(&format!("SET LOCAL search_path TO {}", schema))
+            .execute(&mut *tx)
+            .await?;
+
+        Ok(tx)
+    }
+
+    /// Creates the namespace, or rebuilds it when its tables are older than
+    /// the templates.
+    async fn prepare(&self, pool: &PgPool, name: &str, schema: &str) -> Result<(), NamespaceError> {
+        let mut tx = pool.begin().await?;
+
+        // concurrent creations and rebuilds could collide, so take turns
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('namespaces'))")
+            .execute(&mut *tx)
+            .await?;
+
+        let recorded: Option<i64> =
+            sqlx::query_scalar("SELECT schema_version FROM namespaces WHERE name = $1")
+                .bind(name)
+                .fetch_optional(&mut *tx)
+                .await?;
+        match recorded {
+            Some(version) if version >= SCHEMA_VERSION => return Ok(()),
+            Some(_) => {}
+            None if self.defaults.iter().any(|default| default == name) => {}
+            None => {
+                let count: i64 =
+                    sqlx::query_scalar("SELECT count(*) FROM namespaces WHERE name <> ALL($1)")
+                        .bind(&self.defaults)
+                        .fetch_one(&mut *tx)
+                        .await?;
+                if count >= self.limit {
+                    return Err(NamespaceError::LimitReached(count));
+                }
+            }
+        }
+
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema))
+            .execute(&mut *tx)
+            .await?;
+        for table in TABLES {
+            let exists: Option<String> = sqlx::query_scalar("SELECT to_regclass($1)::TEXT")
+                .bind(format!("{}.{}", schema, table))
+                .fetch_one(&mut *tx)
+                .await?;
+            if exists.is_some() {
+                rebuild(&mut tx, name, schema, table).await?;
+            } else {
+                sqlx::query(&format!(
+                    "CREATE TABLE {schema}.{table} (LIKE public.{table} INCLUDING ALL EXCLUDING COMMENTS)"
+                ))
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        sqlx::query(
+            "INSERT INTO namespaces (name, schema_version) VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE SET schema_version = EXCLUDED.schema_version",
+        )
+        .bind(name)
+        .bind(SCHEMA_VERSION)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Drops the namespace's schema and everything in it.
+    async fn remove(&self, pool: &PgPool, name: &str) -> Result<(), NamespaceError> {
+        let schema = schema_for(name)?;
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('namespaces'))")
+            .execute(&mut *tx)
+            .await?;
+        let removed = sqlx::query("DELETE FROM namespaces WHERE name = $1")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        if removed.rows_affected() == 0 {
+            return Err(NamespaceError::NotFound(name.to_string()));
+        }
+        sqlx::query(&format!("DROP SCHEMA IF EXISTS {} CASCADE", schema))
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        self.ready.write().unwrap().remove(name);
+
+        Ok(())
+    }
+}
+
+/// The quoted schema of a namespace name, which must be 1 to 32 lowercase
+/// letters, digits or underscores.
+fn schema_for(name: &str) -> Result<String, NamespaceError> {
+    let valid = (1..=32).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+    if !valid {
+        return Err(NamespaceError::Invalid);
+    }
+
+    // names are checked above, so quoting them is enough
+    Ok(format!("\"ns_{}\"", name))
+}
+
+/// Replaces a namespace table with a fresh copy of its template, carrying
+/// over the columns both still share.
+async fn rebuild(
+    tx: &mut Transaction<'static, Postgres>,
+    name: &str,
+    schema: &str,
+    table: &str,
+) -> Result<(), NamespaceError> {
+    sqlx::query(&format!(
+        "ALTER TABLE {schema}.{table} RENAME TO {table}_old"
+    ))
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(&format!(
+        "CREATE TABLE {schema}.{table} (LIKE public.{table} INCLUDING ALL EXCLUDING COMMENTS)"
+    ))
+    .execute(&mut **tx)
+    .await?;
+
+    let columns: Option<String> = sqlx::query_scalar(
+        "SELECT string_agg(quote_ident(column_name), ', ' ORDER BY ordinal_position)
+        FROM information_schema.columns
+        WHERE table_schema = 'public' AND table_name = $1 AND column_name IN (
+            SELECT column_name FROM information_schema.columns
+            WHERE table_schema = $2 AND table_name = $3
+        )",
+    )
+    .bind(table)
+    .bind(format!("ns_{}", name))
+    .bind(format!("{}_old", table))
+    .fetch_one(&mut **tx)
+    .await?;
+    if let Some(columns) = columns {
+        sqlx::query(&format!(
+            "INSERT INTO {schema}.{table} ({columns}) SELECT {columns} FROM {schema}.{table}_old"
+        ))
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    sqlx::query(&format!("DROP TABLE {schema}.{table}_old"))
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Every namespace with the template version its tables were built from.
+#[get("/namespaces")]
+pub async fn namespaces_list(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, NamespaceError> {
+    data.namespaces.authorize(&req)?;
+
+    let rows: Vec<(String, i64, String)> = sqlx::query_as(
+        "SELECT name, schema_version, date_trunc('second', created_at)::TEXT
+        FROM namespaces ORDER BY name",
+    )
+    .fetch_all(&data.pool)
+    .await?;
+
+    let namespaces = &data.namespaces;
+    Ok(web::Json(json!({
+        "limit": namespaces.limit,
+        "namespaces": rows.into_iter().map(|(name, version, created_at)| json!({
+            "name": name,
+            "schema_version": version,
+            "current": version >= SCHEMA_VERSION,
+            "created_at": created_at,
+        })).collect::<Vec<_>>(),
+    })))
+}
+
+/// Drops a namespace and its orders. Using it again creates it afresh.
+#[delete("/namespaces/{name}")]
+pub async fn namespaces_delete(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, NamespaceError> {
+    data.namespaces.authorize(&req)?;
+    data.namespaces.remove(&data.pool, &name).await?;
+
+    Ok(HttpResponse::Ok())
+}
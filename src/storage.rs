@@ -0,0 +1,452 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use actix_web::{error::BlockingError, web};
+use async_trait::async_trait;
+use derive_more::{Display, Error};
+use serde::Serialize;
+use shuttle_persist::{PersistError, PersistInstance};
+use sqlx::PgPool;
+
+#[derive(Debug, Display, Error)]
+pub enum StorageError {
+    #[display(fmt = "invalid storage key")]
+    InvalidKey,
+
+    #[display(fmt = "stored value could not be encoded: {}", _0)]
+    Encoding(serde_json::Error),
+
+    #[display(fmt = "storage io failed: {}", _0)]
+    Io(io::Error),
+
+    #[display(fmt = "persist storage failed: {}", _0)]
+    Persist(PersistError),
+
+    #[display(fmt = "database storage failed: {}", _0)]
+    Database(sqlx::Error),
+
+    #[display(fmt = "interrupted before finishing")]
+    Interrupted,
+}
+
+impl From<BlockingError> for StorageError {
+    fn from(_: BlockingError) -> Self {
+        StorageError::Interrupted
+    }
+}
+
+/// Keys become file names in some backends, so every backend accepts the
+/// same conservative set.
+pub fn check_key(key: &str) -> Result<(), StorageError> {
+    let valid = !key.is_empty()
+        && key.len() <= 200
+        && !key.starts_with('.')
+        && !key.contains(['/', '\\', '\0']);
+
+    if valid {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidKey)
+    }
+}
+
+/// A flat key-value store of byte values.
+#[async_trait]
+pub trait KeyValueStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError>;
+
+    /// Returns whether the key existed.
+    async fn remove(&self, key: &str) -> Result<bool, StorageError>;
+
+    /// Every key starting with `prefix`, sorted.
+    async fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError>;
+
+    /// Removes every key starting with `prefix`, returning how many there were.
+    async fn remove_prefix(&self, prefix: &str) -> Result<usize, StorageError>;
+}
+
+impl dyn KeyValueStore + '_ {
+    /// Saves a value as json.
+    pub async fn save<T: Serialize>(&self, key: &str, value: &T) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(value).map_err(StorageError::Encoding)?;
+
+        self.put(key, &value).await
+    }
+}
+
+/// Shuttle's persist volume. Values are written as bincode byte vectors.
+pub struct PersistStore {
+    persist: PersistInstance,
+}
+
+impl PersistStore {
+    pub fn new(persist: PersistInstance) -> Self {
+        PersistStore { persist }
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for PersistStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        check_key(key)?;
+        let (persist, key) = (self.persist.clone(), key.to_string());
+
+        web::block(move || match persist.load::<Vec<u8>>(&key) {
+            Ok(value) => Ok(Some(value)),
+            Err(PersistError::Open(err)) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(StorageError::Persist(err)),
+        })
+        .await?
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        check_key(key)?;
+        let (persist, key, value) = (self.persist.clone(), key.to_string(), value.to_vec());
+
+        web::block(move || persist.save(&key, value).map_err(StorageError::Persist)).await?
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool, StorageError> {
+        check_key(key)?;
+        let (persist, key) = (self.persist.clone(), key.to_string());
+
+        web::block(move || match persist.remove(&key) {
+            Ok(()) => Ok(true),
+            Err(PersistError::RemoveFile(err)) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(false)
+            }
+            Err(err) => Err(StorageError::Persist(err)),
+        })
+        .await?
+    }
+
+    async fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let persist = self.persist.clone();
+        let mut keys = web::block(move || persist.list())
+            .await?
+            .map_err(StorageError::Persist)?;
+        keys.retain(|key| key.starts_with(prefix));
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<usize, StorageError> {
+        let keys = self.keys_with_prefix(prefix).await?;
+        for key in &keys {
+            self.remove(key).await?;
+        }
+
+        Ok(keys.len())
+    }
+}
+
+/// One file per key in a local directory.
+pub struct LocalStore {
+    dir: PathBuf,
+    // numbers temporary files, so concurrent writes of a key never share one
+    writes: AtomicU64,
+}
+
+impl LocalStore {
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(LocalStore {
+            dir: dir.as_ref().to_path_buf(),
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        check_key(key)?;
+
+        Ok(self.dir.join(key))
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for LocalStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let path = self.path(key)?;
+
+        web::block(move || match fs::read(path) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(StorageError::Io(err)),
+        })
+        .await?
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        let write = self.writes.fetch_add(1, Ordering::Relaxed);
+        let temp = self.dir.join(format!(".{}.{}.tmp", key, write));
+        let value = value.to_vec();
+
+        // write then rename, so readers never see half a value
+        web::block(move || {
+            fs::write(&temp, value)
+                .and_then(|()| fs::rename(&temp, path))
+                .map_err(|err| {
+                    let _ = fs::remove_file(&temp);
+                    StorageError::Io(err)
+                })
+        })
+        .await?
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool, StorageError> {
+        let path = self.path(key)?;
+
+        web::block(move || match fs::remove_file(path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(StorageError::Io(err)),
+        })
+        .await?
+    }
+
+    async fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let (dir, prefix) = (self.dir.clone(), prefix.to_string());
+
+        web::block(move || {
+            let mut keys = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if entry.file_type()?.is_file()
+                    && name.starts_with(&prefix)
+                    && check_key(&name).is_ok()
+                {
+                    keys.push(name);
+                }
+            }
+
+            keys.sort();
+            Ok(keys)
+        })
+        .await?
+        .map_err(StorageError::Io)
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<usize, StorageError> {
+        let keys = self.keys_with_prefix(prefix).await?;
+        for key in &keys {
+            self.remove(key).await?;
+        }
+
+        Ok(keys.len())
+    }
+}
+
+/// Lost on restart, for tests and local runs.
+#[derive(Default)]
+pub struct MemoryStore {
+    values: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+#[async_trait]
+impl KeyValueStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        check_key(key)?;
+
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        check_key(key)?;
+
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool, StorageError> {
+        check_key(key)?;
+
+        Ok(self.values.lock().unwrap().remove(key).is_some())
+    }
+
+    async fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let values = self.values.lock().unwrap();
+
+        Ok(values
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<usize, StorageError> {
+        let mut values = self.values.lock().unwrap();
+        let size = values.len();
+        values.retain(|key, _| !key.starts_with(prefix));
+
+        Ok(size - values.len())
+    }
+}
+
+/// Rows of the `kv_store` table the migrations create.
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresStore { pool }
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for PostgresStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        check_key(key)?;
+
+        sqlx::query_scalar("SELECT value FROM kv_store WHERE key = $1")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        check_key(key)?;
+
+        sqlx::query(
+            "INSERT INTO kv_store (key, value) VALUES ($1, $2)
+            ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await
+        .map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool, StorageError> {
+        check_key(key)?;
+
+        let result = sqlx::query("DELETE FROM kv_store WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // left() rather than LIKE, so the prefix needs no escaping
+    async fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        sqlx::query_scalar(
+            "SELECT key FROM kv_store WHERE left(key, char_length($1)) = $1 ORDER BY key",
+        )
+        .bind(prefix)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(StorageError::Database)
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<usize, StorageError> {
+        let result = sqlx::query("DELETE FROM kv_store WHERE left(key, char_length($1)) = $1")
+            .bind(prefix)
+            .execute(&self.pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(result.rows_affected() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trips(store: &dyn KeyValueStore) {
+        assert_eq!(store.get("missing").await.unwrap(), None);
+        assert!(!store.remove("missing").await.unwrap());
+
+        store.put("a", b"first").await.unwrap();
+        store.put("a", b"second").await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(b"second".to_vec()));
+
+        assert!(store.remove("a").await.unwrap());
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert!(!store.remove("a").await.unwrap());
+    }
+
+    async fn lists_and_removes_prefixes(store: &dyn KeyValueStore) {
+        for key in ["packet:b", "packet:a", "other", "packet"] {
+            store.put(key, key.as_bytes()).await.unwrap();
+        }
+
+        assert_eq!(
+            store.keys_with_prefix("packet:").await.unwrap(),
+            ["packet:a", "packet:b"]
+        );
+        assert_eq!(store.remove_prefix("packet:").await.unwrap(), 2);
+        assert_eq!(
+            store.keys_with_prefix("").await.unwrap(),
+            ["other", "packet"]
+        );
+    }
+
+    async fn refuses_invalid_keys(store: &dyn KeyValueStore) {
+        for key in ["", ".hidden", "a/b", "../up", "a\\b"] {
+            assert!(matches!(
+                store.put(key, b"value").await,
+                Err(StorageError::InvalidKey)
+            ));
+            assert!(matches!(
+                store.get(key).await,
+                Err(StorageError::InvalidKey)
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn memory_store() {
+        round_trips(&MemoryStore::default()).await;
+        lists_and_removes_prefixes(&MemoryStore::default()).await;
+        refuses_invalid_keys(&MemoryStore::default()).await;
+    }
+
+    #[actix_web::test]
+    async fn local_store() {
+        let dir = tempfile::tempdir().unwrap();
+        round_trips(&LocalStore::new(dir.path().join("round_trips")).unwrap()).await;
+        lists_and_removes_prefixes(&LocalStore::new(dir.path().join("prefixes")).unwrap()).await;
+        refuses_invalid_keys(&LocalStore::new(dir.path().join("invalid")).unwrap()).await;
+    }
+
+    #[actix_web::test]
+    async fn local_store_writes_of_one_key_do_not_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).unwrap();
+
+        let values: Vec<_> = (0..16).map(|i| vec![i; 4096]).collect();
+        let writes = values.iter().map(|value| store.put("key", value));
+        for result in futures_util::future::join_all(writes).await {
+            result.unwrap();
+        }
+
+        // one whole value wins and no temporary file is left behind
+        let stored = store.get("key").await.unwrap().unwrap();
+        assert!(values.contains(&stored));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+//! Time zones for day12's analysis.
+//!
+//! Named zones come from the tz database bundled by `chrono-tz`, so daylight
+//! saving rules work the same whether or not the host has tzdata installed.
+//! Only UTC to local conversion is needed, which keeps this to looking up an
+//! offset.
+//!
+//! `chrono-tz` lists transitions up to the end of 2099. Past that it keeps the
+//! offset in force at the end of the table, which drops daylight saving, so
+//! named zones refuse later times rather than give a wrong local time.
+
+use chrono::{DateTime, Datelike, FixedOffset, Offset, Utc};
+use chrono_tz::Tz;
+use derive_more::{Display, Error};
+
+// last year chrono-tz has transitions for
+const LAST_LISTED_YEAR: i32 = 2099;
+
+#[derive(Debug, Display, Error)]
+pub enum TzError {
+    #[display(fmt = "unknown time zone {}", _0)]
+    Unknown(#[error(not(source))] String),
+
+    #[display(fmt = "{} has no known rules after {}", _0, LAST_LISTED_YEAR)]
+    PastRules(#[error(not(source))] String),
+}
+
+pub enum Zone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    /// Accepts `UTC`, a fixed offset like `+05:30` or `-08`, or a tz
+    /// database name like `Europe/Oslo`.
+    pub fn parse(name: &str) -> Result<Zone, TzError> {
+        let unknown = || TzError::Unknown(name.to_string());
+
+        if name.eq_ignore_ascii_case("utc") || name == "Z" {
+            return Ok(Zone::Fixed(FixedOffset::east_opt(0).unwrap()));
+        }
+        if name.starts_with(['+', '-']) {
+            let seconds = parse_offset(name).ok_or_else(unknown)?;
+            return FixedOffset::east_opt(seconds)
+                .map(Zone::Fixed)
+                .ok_or_else(unknown);
+        }
+
+        name.parse::<Tz>().map(Zone::Named).map_err(|_| unknown())
+    }
+
+    pub fn offset_at(&self, time: DateTime<Utc>) -> Result<FixedOffset, TzError> {
+        match self {
+            Zone::Fixed(offset) => Ok(*offset),
+            Zone::Named(tz) if time.year() > LAST_LISTED_YEAR => {
+                Err(TzError::PastRules(tz.name().to_string()))
+            }
+            Zone::Named(tz) => Ok(time.with_timezone(tz).offset().fix()),
+        }
+    }
+
+    pub fn to_local(&self, time: DateTime<Utc>) -> Result<DateTime<FixedOffset>, TzError> {
+        Ok(time.with_timezone(&self.offset_at(time)?))
+    }
+}
+
+/// Parses `[+-]hh[:mm[:ss]]` into seconds east of UTC.
+fn parse_offset(text: &str) -> Option<i32> {
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(text) => (-1, text),
+        None => (1, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    let mut seconds = 0;
+    let mut parts = 0;
+    for (part, scale) in text.split(':').zip([3600, 60, 1]) {
+        if part.is_empty() || part.len() > 2 || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        seconds += part.parse::<i32>().ok()? * scale;
+        parts += 1;
+    }
+    if parts != text.split(':').count() {
+        return None;
+    }
+
+    Some(sign * seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offset(zone: &str, time: &str) -> i32 {
+        let time = time.parse::<DateTime<Utc>>().unwrap();
+        Zone::parse(zone)
+            .unwrap()
+            .offset_at(time)
+            .unwrap()
+            .local_minus_utc()
+    }
+
+    #[test]
+    fn follows_daylight_saving() {
+        // europe switches at 01:00 utc on the last sunday of march
+        assert_eq!(offset("Europe/Oslo", "2024-03-31T00:59:59Z"), 3600);
+        assert_eq!(offset("Europe/Oslo", "2024-03-31T01:00:00Z"), 7200);
+        assert_eq!(
+            offset("America/New_York", "2024-01-15T12:00:00Z"),
+            -5 * 3600
+        );
+        assert_eq!(
+            offset("America/New_York", "2024-07-15T12:00:00Z"),
+            -4 * 3600
+        );
+    }
+
+    #[test]
+    fn follows_southern_hemisphere_rules() {
+        // sydney is on daylight time over the new year
+        assert_eq!(
+            offset("Australia/Sydney", "2024-01-15T00:00:00Z"),
+            11 * 3600
+        );
+        assert_eq!(
+            offset("Australia/Sydney", "2024-07-15T00:00:00Z"),
+            10 * 3600
+        );
+        assert_eq!(
+            offset("America/Santiago", "2024-01-15T12:00:00Z"),
+            -3 * 3600
+        );
+    }
+
+    #[test]
+    fn refuses_named_zones_past_the_listed_years() {
+        assert_eq!(offset("Europe/Oslo", "2099-07-01T00:00:00Z"), 7200);
+        assert_eq!(offset("Europe/Oslo", "2099-12-31T23:59:59Z"), 3600);
+        assert_eq!(offset("+02:00", "2150-07-01T00:00:00Z"), 7200);
+
+        let late = "2100-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        for name in ["Europe/Oslo", "Australia/Sydney", "Asia/Kolkata"] {
+            let err = Zone::parse(name).unwrap().to_local(late).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("{} has no known rules after 2099", name)
+            );
+        }
+    }
+
+    #[test]
+    fn reads_zones_without_transitions() {
+        assert_eq!(offset("Asia/Kolkata", "2024-06-01T00:00:00Z"), 19800);
+        assert_eq!(offset("Etc/GMT+5", "2024-06-01T00:00:00Z"), -5 * 3600);
+    }
+
+    #[test]
+    fn reads_fixed_offsets() {
+        assert_eq!(offset("UTC", "2024-06-01T00:00:00Z"), 0);
+        assert_eq!(offset("+05:30", "2024-06-01T00:00:00Z"), 19800);
+        assert_eq!(offset("-08", "2024-06-01T00:00:00Z"), -8 * 3600);
+
+        for name in [
+            "+24",
+            "+5:300",
+            "-",
+            "+01:",
+            "Mars/Olympus",
+            "../etc/passwd",
+            "",
+        ] {
+            assert!(Zone::parse(name).is_err(), "{:?}", name);
+        }
+    }
+}